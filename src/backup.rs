@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Utc};
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+
+use crate::config::BackupConfig;
+
+/// Names are in UTC, so they are unique and in order across daylight saving changes
+const TIMESTAMP_FORMAT: &str = "sal-%Y%m%dT%H%M%SZ.db";
+/// Backups made before names were in UTC, named in local time
+const LOCAL_TIMESTAMP_FORMAT: &str = "sal-%Y%m%dT%H%M%S.db";
+
+/// Copies the database into the backup directory using SQLite's online backup,
+/// which is safe while sal is writing to it. Old backups are pruned afterwards.
+pub fn backup_now(conn: &Connection, config: &BackupConfig) -> io::Result<PathBuf> {
+    backup_at(conn, config, Utc::now())
+}
+
+fn backup_at(conn: &Connection, config: &BackupConfig, now: DateTime<Utc>) -> io::Result<PathBuf> {
+    fs::create_dir_all(&config.dir)?;
+    let name = now.format(TIMESTAMP_FORMAT).to_string();
+    let path = config.dir.join(name);
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists, try again in a second", path.display()),
+        ));
+    }
+    // Written under another name first, so a crash never leaves a partial backup behind
+    let partial = path.with_extension("partial");
+
+    conn.backup(DatabaseName::Main, &partial, None)
+        .map_err(io::Error::other)?;
+    fs::rename(&partial, &path)?;
+
+    for pruned in prune(config)? {
+        fs::remove_file(pruned)?;
+    }
+
+    Ok(path)
+}
+
+/// Backups in the backup directory with their time in UTC, newest first
+pub fn list_backups(dir: &Path) -> io::Result<Vec<(NaiveDateTime, PathBuf)>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut backups = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        let time = NaiveDateTime::parse_from_str(&name, TIMESTAMP_FORMAT)
+            .ok()
+            .or_else(|| {
+                let local = NaiveDateTime::parse_from_str(&name, LOCAL_TIMESTAMP_FORMAT).ok()?;
+                Some(Local.from_local_datetime(&local).earliest()?.naive_utc())
+            });
+        if let Some(time) = time {
+            backups.push((time, path));
+        }
+    }
+    backups.sort_by(|a, b| b.cmp(a));
+    Ok(backups)
+}
+
+/// Backups falling outside the retention policy
+fn prune(config: &BackupConfig) -> io::Result<Vec<PathBuf>> {
+    let backups = list_backups(&config.dir)?;
+    let keep = to_keep(
+        &backups.iter().map(|(time, _)| *time).collect::<Vec<_>>(),
+        config,
+    );
+    Ok(backups
+        .into_iter()
+        .filter(|(time, _)| !keep.contains(time))
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Identifies the day, week or month a backup was made in
+type Period = fn(&NaiveDateTime) -> (i32, u32);
+
+/// Keeps the newest backup of each of the last `keep_daily` days, `keep_weekly` weeks
+/// and `keep_monthly` months that have backups. `times` must be sorted newest first.
+fn to_keep(times: &[NaiveDateTime], config: &BackupConfig) -> HashSet<NaiveDateTime> {
+    let mut keep = HashSet::new();
+    keep.extend(times.first());
+
+    let periods: [(usize, Period); 3] = [
+        (config.keep_daily, |t| (t.year(), t.ordinal())),
+        (config.keep_weekly, |t| {
+            (t.iso_week().year(), t.iso_week().week())
+        }),
+        (config.keep_monthly, |t| (t.year(), t.month())),
+    ];
+    for (n, period) in periods {
+        let mut seen = HashSet::new();
+        for time in times {
+            if seen.len() == n {
+                break;
+            }
+            if seen.insert(period(time)) {
+                keep.insert(*time);
+            }
+        }
+    }
+    keep
+}
+
+/// Checks that every backup can be opened and passes SQLite's integrity check
+pub fn verify(config: &BackupConfig) -> io::Result<()> {
+    let backups = list_backups(&config.dir)?;
+    if backups.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No backups in {}", config.dir.display()),
+        ));
+    }
+
+    let mut failed = 0;
+    for (_, path) in &backups {
+        match integrity_check(path) {
+            Ok(()) => println!("OK      {}", path.display()),
+            Err(err) => {
+                println!("FAILED  {}: {err}", path.display());
+                failed += 1;
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{failed} of {} backups failed verification",
+            backups.len()
+        ))),
+    }
+}
+
+fn integrity_check(path: &Path) -> Result<(), String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|err| err.to_string())?;
+    let result: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(|err| err.to_string())?;
+    if result != "ok" {
+        return Err(result);
+    }
+    // A backup without the logs table would pass the integrity check, but is useless
+    conn.query_row("SELECT COUNT(*) FROM logs", [], |row| row.get::<_, u64>(0))
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::{NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+
+    use super::{backup_at, backup_now, list_backups, to_keep, verify};
+    use crate::config::BackupConfig;
+    use crate::migrate::create_tables;
+    use crate::models::open_db;
+
+    fn config(dir: PathBuf) -> BackupConfig {
+        BackupConfig {
+            dir,
+            keep_daily: 3,
+            keep_weekly: 2,
+            keep_monthly: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retention_keeps_newest_per_period() {
+        let start = NaiveDate::from_ymd_opt(2025, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        // Two backups a day for 60 days, newest first
+        let times: Vec<NaiveDateTime> = (0..120)
+            .map(|i| start + TimeDelta::hours(12 * i))
+            .rev()
+            .collect();
+        let keep = to_keep(&times, &config(PathBuf::new()));
+
+        let time = |m, d, h| {
+            NaiveDate::from_ymd_opt(2025, m, d)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap()
+        };
+        let mut kept: Vec<_> = keep.into_iter().collect();
+        kept.sort();
+        // Three days, the end of the previous week and the end of each of the previous months.
+        // Only the newest backup of a day is kept.
+        assert_eq!(
+            kept,
+            vec![
+                time(1, 31, 12),
+                time(2, 23, 12),
+                time(2, 28, 12),
+                time(3, 1, 12),
+                time(3, 2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn backups_are_written_and_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_db(&dir.path().join("sal.db"));
+        create_tables(&conn).unwrap();
+        let config = config(dir.path().join("backups"));
+
+        let path = backup_now(&conn, &config).unwrap();
+        assert!(path.exists());
+        assert_eq!(list_backups(&config.dir).unwrap().len(), 1);
+        verify(&config).unwrap();
+
+        std::fs::write(&path, "not a database").unwrap();
+        assert!(verify(&config).is_err());
+    }
+
+    #[test]
+    fn backups_are_named_in_utc_and_never_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_db(&dir.path().join("sal.db"));
+        create_tables(&conn).unwrap();
+        let config = config(dir.path().join("backups"));
+        // Made before names were in UTC
+        std::fs::create_dir_all(&config.dir).unwrap();
+        std::fs::copy(
+            dir.path().join("sal.db"),
+            config.dir.join("sal-20251020T120000.db"),
+        )
+        .unwrap();
+
+        // 02:30 in Oslo, the second time that night as the clocks go back
+        let now = Utc.with_ymd_and_hms(2025, 10, 26, 1, 30, 0).unwrap();
+        let path = backup_at(&conn, &config, now).unwrap();
+        assert!(path.ends_with("sal-20251026T013000Z.db"));
+        let err = backup_at(&conn, &config, now).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        let names: Vec<_> = list_backups(&config.dir)
+            .unwrap()
+            .into_iter()
+            .map(|(_, path)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, ["sal-20251026T013000Z.db", "sal-20251020T120000.db"]);
+    }
+}
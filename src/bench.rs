@@ -0,0 +1,85 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use itertools::Itertools;
+
+use crate::migrate::create_tables;
+use crate::models::{calendar, get_days, get_users, ids_for_username, open_db, rollover_date};
+
+/// The timing `share` of the way from the fastest to the slowest
+fn percentile(sorted: &[Duration], share: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * share).round() as usize]
+}
+
+/// Timings of one function over every user
+#[derive(Debug, Default)]
+struct Timings(Vec<Duration>);
+
+impl Timings {
+    fn time<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.0.push(started.elapsed());
+        result
+    }
+
+    fn print(&self, name: &str) {
+        let sorted = self.0.iter().copied().sorted().collect_vec();
+        let total: Duration = sorted.iter().sum();
+        println!(
+            "{name:<12} {:>12?} {:>12?} {:>12?} {:>12?} {:>12?}",
+            percentile(&sorted, 0.5),
+            percentile(&sorted, 0.95),
+            sorted.last().unwrap(),
+            total.checked_div(sorted.len() as u32).unwrap(),
+            total,
+        );
+    }
+}
+
+/// Times `get_days` and `calendar` for every user in `db`, `runs` times, like when each of
+/// them beeps
+pub fn bench(db: &Path, runs: usize) -> io::Result<()> {
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    let users = get_users(&conn)
+        .into_iter()
+        .map(|(username, ..)| ids_for_username(&conn, &username).unwrap())
+        .collect_vec();
+    if runs == 0 {
+        return Err(io::Error::other("Give at least one run"));
+    }
+    if users.is_empty() {
+        return Err(io::Error::other(format!(
+            "Found no users in {}",
+            db.display()
+        )));
+    }
+
+    let mut get_days_timings = Timings::default();
+    let mut calendar_timings = Timings::default();
+    let today = rollover_date(Utc::now());
+    let mut days = 0;
+    for _ in 0..runs {
+        for ids in &users {
+            let user_days = get_days_timings.time(|| get_days(&conn, ids, None).unwrap());
+            calendar_timings.time(|| calendar(&user_days, today).count());
+            days += user_days.len();
+        }
+    }
+
+    println!(
+        "{} users with {} days on average, {runs} runs",
+        users.len(),
+        days / (users.len() * runs)
+    );
+    println!(
+        "{:<12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "", "median", "p95", "max", "mean", "total"
+    );
+    get_days_timings.print("get_days");
+    calendar_timings.print("calendar");
+    Ok(())
+}
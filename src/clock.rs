@@ -0,0 +1,74 @@
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+
+use crate::models::rollover_date;
+
+/// Where the current time comes from, so rollover and streaks can be tried at any moment
+pub trait Clock: Debug {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// The date beeps made now count towards
+    fn today(&self) -> NaiveDate {
+        rollover_date(self.now())
+    }
+}
+
+/// The real time
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Runs at normal speed from another starting time, for `--fake-now`
+#[derive(Debug)]
+pub struct OffsetClock {
+    offset: TimeDelta,
+}
+
+impl OffsetClock {
+    pub fn starting_at(start: DateTime<Utc>) -> Self {
+        Self {
+            offset: start - Utc::now(),
+        }
+    }
+}
+
+impl Clock for OffsetClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset
+    }
+}
+
+/// Lets a clock be moved by whoever else holds it
+impl<C: Clock + ?Sized> Clock for Rc<C> {
+    fn now(&self) -> DateTime<Utc> {
+        self.as_ref().now()
+    }
+}
+
+/// Stands still until moved, for tests and `replay`
+#[derive(Debug)]
+pub struct FixedClock(Cell<DateTime<Utc>>);
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Cell::new(now))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.0.set(now);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0.get()
+    }
+}
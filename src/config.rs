@@ -0,0 +1,401 @@
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+use json::JsonValue;
+use ratatui::style::Color;
+
+use crate::events::Event;
+use crate::github_map::{supports_truecolor, ColorScale, Metric, Palette};
+use crate::models::DEFAULT_LOCATION;
+
+pub const CONFIG_PATH: &str = "sal.json";
+
+/// Settings read from `sal.json`. Every field is optional in the file.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Room of the card reader this instance registers beeps from
+    pub location: String,
+    pub heatmap: HeatmapConfig,
+    pub backup: BackupConfig,
+    pub server: ServerConfig,
+    pub metrics: MetricsConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    /// Disabled when not set
+    pub mqtt: Option<MqttConfig>,
+    /// Central server this kiosk uploads to. Disabled when not set.
+    pub sync: Option<SyncConfig>,
+    /// Old beeps are kept forever when not set
+    pub retention: Option<RetentionConfig>,
+    /// Card IDs are stored in clear when not set
+    pub card_hash: Option<CardHashConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeatmapConfig {
+    /// Metric shown when a user beeps
+    pub metric: Metric,
+    pub palette: Palette,
+    /// Detected from `COLORTERM` when not set
+    pub truecolor: bool,
+    /// Overrides of `Metric::default_thresholds`
+    pub thresholds: HashMap<Metric, Vec<u64>>,
+}
+
+impl Default for HeatmapConfig {
+    fn default() -> Self {
+        Self {
+            metric: Metric::Duration,
+            palette: Palette::Standard,
+            truecolor: supports_truecolor(),
+            thresholds: HashMap::new(),
+        }
+    }
+}
+
+impl HeatmapConfig {
+    pub fn scale(&self, metric: Metric) -> ColorScale {
+        let thresholds = self
+            .thresholds
+            .get(&metric)
+            .cloned()
+            .unwrap_or_else(|| metric.default_thresholds());
+        ColorScale::new(metric, thresholds, &self.palette, self.truecolor)
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Some(metric) = value["metric"].as_str() {
+            config.metric = Metric::from_str(metric)?;
+        }
+        if let Some(palette) = value["palette"].as_str() {
+            config.palette = Palette::from_str(palette)?;
+        } else if value["palette"].is_array() {
+            let colors = value["palette"]
+                .members()
+                .map(|color| {
+                    let color = color.as_str().ok_or("Palette colours must be strings")?;
+                    Color::from_str(color)
+                        .map_err(|_| format!("Invalid colour {color:?} in palette"))
+                })
+                .collect::<Result<_, _>>()?;
+            config.palette = Palette::Custom(colors);
+        }
+        if let Some(truecolor) = value["truecolor"].as_bool() {
+            config.truecolor = truecolor;
+        }
+        for metric in Metric::ALL {
+            let thresholds = &value["thresholds"][metric.key()];
+            if thresholds.is_array() {
+                let thresholds: Vec<u64> = thresholds
+                    .members()
+                    .map(|t| t.as_u64().ok_or("Thresholds must be positive integers"))
+                    .collect::<Result<_, _>>()?;
+                // Each threshold starts a bucket, so they must come in order
+                if thresholds.is_empty() || !thresholds.is_sorted_by(|a, b| a < b) {
+                    return Err(format!(
+                        "Thresholds for {} must be a non-empty, strictly ascending list",
+                        metric.key()
+                    ));
+                }
+                config.thresholds.insert(metric, thresholds);
+            }
+        }
+        if let Palette::Custom(colors) = &config.palette {
+            if colors.is_empty() {
+                return Err("Custom palette cannot be empty".to_string());
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// Back up on startup and once a day while the app is running
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Number of days, weeks and months to keep the newest backup of
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from("backups"),
+            keep_daily: 14,
+            keep_weekly: 8,
+            keep_monthly: 24,
+        }
+    }
+}
+
+impl BackupConfig {
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Some(enabled) = value["enabled"].as_bool() {
+            config.enabled = enabled;
+        }
+        if let Some(dir) = value["dir"].as_str() {
+            config.dir = PathBuf::from(dir);
+        }
+        for (key, field) in [
+            ("keep_daily", &mut config.keep_daily),
+            ("keep_weekly", &mut config.keep_weekly),
+            ("keep_monthly", &mut config.keep_monthly),
+        ] {
+            if let Some(n) = value[key].as_usize() {
+                *field = n;
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address `sal serve` listens on
+    pub addr: String,
+    /// Bearer token required to register beeps over HTTP. Registering is disabled without one.
+    pub token: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".to_string(),
+            token: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Some(addr) = value["addr"].as_str() {
+            config.addr = addr.to_string();
+        }
+        config.token = value["token"].as_str().map(str::to_string);
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// Address the app serves Prometheus metrics on. Disabled when not set.
+    pub addr: Option<String>,
+}
+
+impl MetricsConfig {
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        Ok(Self {
+            addr: value["addr"].as_str().map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Keys of the events to send, or every event if empty
+    pub events: Vec<String>,
+    /// Signs each body with HMAC-SHA256 when set
+    pub secret: Option<String>,
+    /// JSON sent instead of the default payload, with `{field}` in strings replaced by payload fields
+    pub template: Option<JsonValue>,
+}
+
+impl WebhookConfig {
+    pub fn listens_to(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|key| key == event.key())
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let url = value["url"].as_str().ok_or("Webhooks must have a url")?;
+        let events = value["events"]
+            .members()
+            .map(|event| {
+                let event = event.as_str().ok_or("Webhook events must be strings")?;
+                match ["beep", "arrival", "departure", "achievement"].contains(&event) {
+                    true => Ok(event.to_string()),
+                    false => Err(format!(
+                        "Unknown webhook event {event:?}, expected beep, arrival, departure or achievement"
+                    )),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            url: url.to_string(),
+            events,
+            secret: value["secret"].as_str().map(str::to_string),
+            template: (!value["template"].is_null()).then(|| value["template"].clone()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topics are `<prefix>/presence`, `<prefix>/occupancy` and `<prefix>/command`
+    pub prefix: String,
+}
+
+impl MqttConfig {
+    fn from_json(value: &JsonValue) -> Result<Option<Self>, String> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            host: value["host"]
+                .as_str()
+                .ok_or("mqtt must have a host")?
+                .to_string(),
+            port: value["port"].as_u16().unwrap_or(1883),
+            client_id: value["client_id"].as_str().unwrap_or("sal").to_string(),
+            username: value["username"].as_str().map(str::to_string),
+            password: value["password"].as_str().map(str::to_string),
+            prefix: value["prefix"].as_str().unwrap_or("sal").to_string(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    /// Base URL of the central `sal serve`, e.g. `http://sal.local:8080`
+    pub server: String,
+    /// The central server's `server.token`
+    pub token: Option<String>,
+    pub interval_seconds: u64,
+}
+
+impl SyncConfig {
+    pub fn new(server: &str) -> Self {
+        Self::from_json(&json::object! { server: server })
+            .unwrap()
+            .unwrap()
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Option<Self>, String> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            server: value["server"]
+                .as_str()
+                .ok_or("sync must have a server")?
+                .trim_end_matches('/')
+                .to_string(),
+            token: value["token"].as_str().map(str::to_string),
+            interval_seconds: value["interval_seconds"].as_u64().unwrap_or(60),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Beeps older than this are collapsed into the first and last of each day
+    pub months: u32,
+}
+
+impl RetentionConfig {
+    fn from_json(value: &JsonValue) -> Result<Option<Self>, String> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            months: value["months"]
+                .as_u32()
+                .ok_or("retention must have a number of months")?,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CardHashConfig {
+    /// Secret used to hash card IDs, created if it does not exist
+    pub key_file: PathBuf,
+}
+
+impl CardHashConfig {
+    fn from_json(value: &JsonValue) -> Result<Option<Self>, String> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            key_file: PathBuf::from(value["key_file"].as_str().unwrap_or("sal.key")),
+        }))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_json(&JsonValue::Null).unwrap()
+    }
+}
+
+impl Config {
+    /// Loads `sal.json` from the working directory, falling back to defaults if it does not exist.
+    /// Returns what is wrong with an invalid one.
+    pub fn try_load() -> Result<Self, String> {
+        let Ok(contents) = fs::read_to_string(CONFIG_PATH) else {
+            return Ok(Self::default());
+        };
+        let value =
+            json::parse(&contents).map_err(|err| format!("Invalid {CONFIG_PATH}: {err}"))?;
+        Self::from_json(&value).map_err(|err| format!("Invalid {CONFIG_PATH}: {err}"))
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        Ok(Self {
+            location: value["location"]
+                .as_str()
+                .unwrap_or(DEFAULT_LOCATION)
+                .to_string(),
+            heatmap: HeatmapConfig::from_json(&value["heatmap"])?,
+            backup: BackupConfig::from_json(&value["backup"])?,
+            server: ServerConfig::from_json(&value["server"])?,
+            metrics: MetricsConfig::from_json(&value["metrics"])?,
+            webhooks: value["webhooks"]
+                .members()
+                .map(WebhookConfig::from_json)
+                .collect::<Result<_, _>>()?,
+            mqtt: MqttConfig::from_json(&value["mqtt"])?,
+            sync: SyncConfig::from_json(&value["sync"])?,
+            retention: RetentionConfig::from_json(&value["retention"])?,
+            card_hash: CardHashConfig::from_json(&value["card_hash"])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn invalid_settings_are_errors() {
+        let config = Config::from_json(&json::object! { location: "kjeller" }).unwrap();
+        assert_eq!(config.location, "kjeller");
+
+        let err = Config::from_json(&json::object! { heatmap: { palette: ["#zz0000"] } });
+        assert_eq!(err.unwrap_err(), "Invalid colour \"#zz0000\" in palette");
+        let err = Config::from_json(&json::object! { webhooks: [{ events: ["beep"] }] });
+        assert_eq!(err.unwrap_err(), "Webhooks must have a url");
+        let err = Config::from_json(&json::object! { mqtt: { port: 1883 } });
+        assert_eq!(err.unwrap_err(), "mqtt must have a host");
+        for thresholds in [json::array![], json::array![4, 1], json::array![1, 1, 4]] {
+            let err = Config::from_json(&json::object! {
+                heatmap: { thresholds: { duration: thresholds } }
+            });
+            assert_eq!(
+                err.unwrap_err(),
+                "Thresholds for duration must be a non-empty, strictly ascending list"
+            );
+        }
+    }
+}
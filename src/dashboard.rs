@@ -0,0 +1,198 @@
+use std::fmt::Write;
+
+use chrono::{NaiveDate, TimeDelta, Utc};
+use chrono_tz::Europe::Oslo;
+use itertools::Itertools;
+use rusqlite::Connection;
+
+use crate::clock::SystemClock;
+use crate::config::HeatmapConfig;
+use crate::github_map::{css_color, GithubMap, Metric};
+use crate::models::{get_leaderboard, get_locations, get_occupancy, rollover_date, Person};
+
+/// Seconds between reloads of the page
+const REFRESH: u32 = 60;
+
+/// Days counted by the leaderboard
+pub const LEADERBOARD_DAYS: i64 = 30;
+
+/// Weeks of history in each heatmap
+const WEEKS: u16 = 26;
+
+const STYLE: &str = "
+body { background: #111; color: #eee; font-family: sans-serif; margin: 2em; }
+h1, h2 { font-weight: normal; }
+.columns { display: flex; gap: 4em; }
+table { border-collapse: collapse; font-size: 1.2em; }
+td, th { padding: 0.2em 0.8em; text-align: left; }
+.number { text-align: right; }
+.present { color: #ffd700; }
+.heatmap { margin-bottom: 1.5em; }
+.legend span { display: inline-block; padding: 0.1em 0.5em; margin-right: 0.3em; color: #fff; text-shadow: 0 0 3px #000; }
+";
+
+/// The wall display: who is in the reading room, the leaderboard and a heatmap per user.
+/// Shows every room unless `location` is given.
+pub fn render(
+    conn: &Connection,
+    heatmap: &HeatmapConfig,
+    metric: Metric,
+    location: Option<&str>,
+) -> String {
+    let today = rollover_date(Utc::now());
+    let present = get_occupancy(conn, today, location).unwrap();
+    let leaderboard =
+        get_leaderboard(conn, Some(leaderboard_start(today)), None, location).unwrap();
+    let title = match location {
+        Some(location) => format!("Salstatistikk: {}", escape(location)),
+        None => "Salstatistikk".to_string(),
+    };
+    // The dashboard is viewed in a browser, which always has all colours
+    let heatmap = HeatmapConfig {
+        truecolor: true,
+        ..heatmap.clone()
+    };
+    let scale = heatmap.scale(metric);
+
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html>
+<html lang=\"no\">
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"{REFRESH}\">
+<title>{title}</title>
+<style>{STYLE}</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p>Oppdatert {}</p>
+<div class=\"columns\">
+<div>
+<h2>På sal nå: {}</h2>",
+        Utc::now().with_timezone(&Oslo).format("%H:%M"),
+        present.len(),
+    )
+    .unwrap();
+    let locations = get_locations(conn).unwrap();
+    if location.is_none() && locations.len() > 1 {
+        let rooms = locations
+            .iter()
+            .map(|(location, _)| {
+                let count = get_occupancy(conn, today, Some(location)).unwrap().len();
+                format!("{}: {count}", escape(location))
+            })
+            .join(" · ");
+        write!(html, "<p>{rooms}</p>").unwrap();
+    }
+    html.push_str("<ul>");
+    for username in &present {
+        write!(html, "<li class=\"present\">{}</li>", escape(username)).unwrap();
+    }
+    write!(
+        html,
+        "</ul>
+</div>
+<div>
+<h2>Flest dager siste {LEADERBOARD_DAYS} dager</h2>
+<table>
+<tr><th>#</th><th>Navn</th><th class=\"number\">Dager</th><th class=\"number\">Timer</th></tr>"
+    )
+    .unwrap();
+    for (i, (username, days, total)) in leaderboard.iter().enumerate() {
+        let class = if present.contains(username) {
+            " class=\"present\""
+        } else {
+            ""
+        };
+        write!(
+            html,
+            "<tr{class}><td>{}</td><td>{}</td><td class=\"number\">{days}</td><td class=\"number\">{}</td></tr>",
+            i + 1,
+            escape(username),
+            total.num_hours(),
+        )
+        .unwrap();
+    }
+    write!(
+        html,
+        "</table>
+</div>
+</div>
+<h2>Oppmøtehistorikk: {}</h2>
+<p class=\"legend\">",
+        metric.title()
+    )
+    .unwrap();
+    for (text, color) in scale.buckets() {
+        write!(
+            html,
+            "<span style=\"background: {}\">{}</span>",
+            css_color(color),
+            escape(&text)
+        )
+        .unwrap();
+    }
+    html.push_str("</p>\n");
+    for (username, _, _) in &leaderboard {
+        let Ok(Some(user)) = Person::find(conn, &SystemClock, username, location) else {
+            continue;
+        };
+        let map = GithubMap::new(&user.stats.days, &scale, today);
+        writeln!(
+            html,
+            "<div class=\"heatmap\"><h3>{}</h3>{}</div>",
+            escape(username),
+            map.to_svg(WEEKS)
+        )
+        .unwrap();
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+pub fn leaderboard_start(today: NaiveDate) -> NaiveDate {
+    today - TimeDelta::days(LEADERBOARD_DAYS - 1)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::config::HeatmapConfig;
+    use crate::fixtures::{today, Fixture};
+    use crate::github_map::Metric;
+
+    #[test]
+    fn dashboard_shows_present_users_and_heatmaps() {
+        let conn = Fixture::new()
+            .beeps_in("sal", 1234567890, &[&today("08:00")])
+            .beeps_in("kjeller", 1234567891, &[&today("08:00")])
+            .user("<ola>", &[1234567890])
+            .build();
+
+        let html = render(&conn, &HeatmapConfig::default(), Metric::Duration, None);
+        assert!(html.contains("På sal nå: 2"));
+        assert!(html.contains("<p>kjeller: 1 · sal: 1</p>"));
+        assert!(html.contains("<li class=\"present\">&lt;ola&gt;</li>"));
+        assert_eq!(html.matches("<svg").count(), 2);
+        assert!(!html.contains("<ola>"));
+
+        let html = render(
+            &conn,
+            &HeatmapConfig::default(),
+            Metric::Duration,
+            Some("kjeller"),
+        );
+        assert!(html.contains("<h1>Salstatistikk: kjeller</h1>"));
+        assert!(html.contains("På sal nå: 1"));
+        assert_eq!(html.matches("<svg").count(), 1);
+    }
+}
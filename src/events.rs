@@ -0,0 +1,134 @@
+use json::JsonValue;
+
+use crate::models::Person;
+
+/// Streak lengths worth celebrating
+const STREAK_MILESTONES: [usize; 6] = [5, 10, 20, 50, 100, 200];
+
+/// Something that happened when a user beeped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Every beep
+    Beep,
+    /// First beep of the day
+    Arrival,
+    /// Every second beep of the day, as users beep both in and out
+    Departure,
+    Achievement(Achievement),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Achievement {
+    /// Arrived on a day completing a streak of this many days
+    Streak(usize),
+    /// Today is the longest day so far
+    LongestDay,
+    /// Today's arrival is the earliest so far
+    EarliestArrival,
+}
+
+impl Event {
+    /// Name used in `sal.json` and payloads
+    pub fn key(&self) -> &'static str {
+        match self {
+            Event::Beep => "beep",
+            Event::Arrival => "arrival",
+            Event::Departure => "departure",
+            Event::Achievement(_) => "achievement",
+        }
+    }
+
+    /// The event with the user's stats, as sent to webhooks
+    pub fn payload(&self, user: &Person) -> JsonValue {
+        // Events only follow a beep, so the latest day is today
+        let today = &user.stats.latest;
+        let (achievement, achievement_text) = match self {
+            Event::Achievement(achievement) => (achievement.key(), achievement.text()),
+            _ => ("", String::new()),
+        };
+        json::object! {
+            event: self.key(),
+            username: user.username.as_str(),
+            date: today.date.to_string(),
+            time: today.end.format("%H:%M").to_string(),
+            timestamp: today.end.to_rfc3339(),
+            arrival: today.start.to_rfc3339(),
+            duration_seconds: today.span().num_seconds(),
+            beeps: today.beeps,
+            streak: user.stats.streak,
+            achievement: achievement,
+            achievement_text: achievement_text,
+        }
+    }
+}
+
+impl Achievement {
+    pub fn key(&self) -> &'static str {
+        match self {
+            Achievement::Streak(_) => "streak",
+            Achievement::LongestDay => "longest_day",
+            Achievement::EarliestArrival => "earliest_arrival",
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            Achievement::Streak(n) => format!("{n} dager på rad"),
+            Achievement::LongestDay => "Lengste dag hittil".to_string(),
+            Achievement::EarliestArrival => "Tidligste ankomst hittil".to_string(),
+        }
+    }
+}
+
+/// Events caused by the beep just registered for `user`
+pub fn events_for(user: &Person) -> Vec<Event> {
+    let stats = &user.stats;
+    let today = &stats.latest;
+    // Records are only worth announcing once there is something to beat
+    let has_history = stats.days.len() > 1;
+
+    let mut events = vec![Event::Beep];
+    if today.beeps == 1 {
+        events.push(Event::Arrival);
+        if STREAK_MILESTONES.contains(&stats.streak) {
+            events.push(Event::Achievement(Achievement::Streak(stats.streak)));
+        }
+        if has_history && stats.earliest_arrival.date == today.date {
+            events.push(Event::Achievement(Achievement::EarliestArrival));
+        }
+    } else if today.beeps.is_multiple_of(2) {
+        events.push(Event::Departure);
+        if has_history && stats.longest_day.date == today.date {
+            events.push(Event::Achievement(Achievement::LongestDay));
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{events_for, Event};
+    use crate::clock::SystemClock;
+    use crate::fixtures::Fixture;
+    use crate::models::Person;
+
+    #[test]
+    fn beeps_alternate_between_arrival_and_departure() {
+        let times = [
+            "2025-02-10 08:00",
+            "2025-02-10 12:00",
+            "2025-02-10 13:00",
+            "2025-02-10 16:00",
+        ];
+        // Events of the last of the first `beeps` beeps
+        let events = |beeps| {
+            let conn = Fixture::new().beeps(1234567890, &times[..beeps]).build();
+            events_for(&Person::load(&conn, &SystemClock, 1234567890).unwrap())
+        };
+
+        assert_eq!(events(1), vec![Event::Beep, Event::Arrival]);
+        assert_eq!(events(2), vec![Event::Beep, Event::Departure]);
+        assert_eq!(events(3), vec![Event::Beep]);
+        assert_eq!(events(4), vec![Event::Beep, Event::Departure]);
+    }
+}
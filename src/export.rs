@@ -0,0 +1,324 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+use rusqlite::Connection;
+
+use crate::models::{get_all_days, get_days, ids_for_username, is_hidden};
+
+/// Standard VTIMEZONE definition for the TZID used by every event
+const OSLO_VTIMEZONE: &str = "BEGIN:VTIMEZONE
+TZID:Europe/Oslo
+BEGIN:DAYLIGHT
+TZOFFSETFROM:+0100
+TZOFFSETTO:+0200
+TZNAME:CEST
+DTSTART:19700329T020000
+RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU
+END:DAYLIGHT
+BEGIN:STANDARD
+TZOFFSETFROM:+0200
+TZOFFSETTO:+0100
+TZNAME:CET
+DTSTART:19701025T030000
+RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU
+END:STANDARD
+END:VTIMEZONE";
+
+/// Writes one event per day the user has been at the reading room, from first to last beep
+pub fn export_ical(conn: &Connection, username: &str, output: Option<PathBuf>) -> io::Result<()> {
+    let ids = ids_for_username(conn, username).map_err(io::Error::other)?;
+    let Some(uid) = ids.iter().min().copied() else {
+        return Err(io::Error::other(format!("Found no user named {username}")));
+    };
+    if is_hidden(conn, &ids).map_err(io::Error::other)? {
+        return Err(io::Error::other(format!(
+            "{username} has hidden their profile"
+        )));
+    }
+    let days = get_days(conn, &ids, None).map_err(io::Error::other)?;
+
+    let dtstamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//sal//Salstatistikk//NO".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+    ];
+    lines.extend(OSLO_VTIMEZONE.lines().map(str::to_string));
+    for day in days.iter().rev() {
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            // Based on the card ID since usernames can change
+            format!("UID:{}-{uid}@sal", day.date.format("%Y%m%d")),
+            format!("DTSTAMP:{dtstamp}"),
+            format!("DTSTART;TZID=Europe/Oslo:{}", ical_local_time(day.start)),
+            format!("DTEND;TZID=Europe/Oslo:{}", ical_local_time(day.end)),
+            "SUMMARY:Lesesal".to_string(),
+            format!("DESCRIPTION:{} bip", day.beeps),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{username}.ics")));
+    // iCalendar requires CRLF line endings
+    fs::write(&output, lines.join("\r\n") + "\r\n")?;
+    println!("Wrote {} days to {}", days.len(), output.display());
+
+    Ok(())
+}
+
+fn ical_local_time(time: DateTime<Tz>) -> String {
+    time.format("%Y%m%dT%H%M%S").to_string()
+}
+
+const TABLE_HEADER: [&str; 7] = [
+    "date",
+    "username",
+    "first",
+    "last",
+    "duration_seconds",
+    "beeps",
+    "coffee",
+];
+
+pub enum TableFormat {
+    Csv,
+    Json,
+}
+
+/// Writes one row per user per day. Dates follow the 05:00 rollover, times are in Oslo time.
+pub fn export_table(
+    conn: &Connection,
+    format: TableFormat,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    username: Option<&str>,
+    location: Option<&str>,
+    output: Option<PathBuf>,
+) -> io::Result<()> {
+    let days = get_all_days(conn, from, to, username, location).map_err(io::Error::other)?;
+
+    let mut writer: Box<dyn Write> = match &output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+
+    match format {
+        TableFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut writer);
+            writer.write_record(TABLE_HEADER)?;
+            for (username, day) in &days {
+                writer.write_record([
+                    day.date.to_string(),
+                    username.clone(),
+                    day.start.to_rfc3339(),
+                    day.end.to_rfc3339(),
+                    day.span().num_seconds().to_string(),
+                    day.beeps.to_string(),
+                    day.coffee.to_string(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+        TableFormat::Json => {
+            let rows = days
+                .iter()
+                .map(|(username, day)| {
+                    json::object! {
+                        date: day.date.to_string(),
+                        username: username.as_str(),
+                        first: day.start.to_rfc3339(),
+                        last: day.end.to_rfc3339(),
+                        duration_seconds: day.span().num_seconds(),
+                        beeps: day.beeps,
+                        coffee: day.coffee,
+                    }
+                })
+                .collect::<Vec<_>>();
+            writer.write_all(json::stringify_pretty(rows, 2).as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    if let Some(path) = output {
+        eprintln!("Wrote {} rows to {}", days.len(), path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::NaiveDate;
+
+    use super::{export_ical, export_table, TableFormat};
+    use crate::fixtures::Fixture;
+    use crate::models::set_visibility;
+
+    #[test]
+    fn tables_have_one_row_per_user_per_day() {
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-10 08:00", "2025-02-10 16:00"])
+            .beeps(1234567891, &["2025-02-11 09:00"])
+            .beeps(1234567892, &["2025-02-11 10:00"])
+            .user("ola", &[1234567890, 1234567891])
+            .build();
+        set_visibility(&conn, 1234567892, true, chrono::Utc::now()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("days.csv");
+
+        export_table(
+            &conn,
+            TableFormat::Csv,
+            None,
+            None,
+            None,
+            None,
+            Some(output.clone()),
+        )
+        .unwrap();
+        let csv = fs::read_to_string(output).unwrap();
+        let rows: Vec<_> = csv.lines().skip(1).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("2025-02-10,ola,2025-02-10T08:00:00+01:00,"));
+        assert!(rows[0].ends_with(",28800,2,0"));
+        assert!(rows[1].starts_with("2025-02-11,ola,"));
+    }
+
+    /// Exports `conn` as JSON with the filters, returning the rows
+    fn json_rows(
+        conn: &rusqlite::Connection,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        username: Option<&str>,
+        location: Option<&str>,
+    ) -> json::JsonValue {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("days.json");
+        export_table(
+            conn,
+            TableFormat::Json,
+            from,
+            to,
+            username,
+            location,
+            Some(output.clone()),
+        )
+        .unwrap();
+        json::parse(&fs::read_to_string(output).unwrap()).unwrap()
+    }
+
+    fn visits() -> rusqlite::Connection {
+        Fixture::new()
+            .beeps(1234567890, &["2025-02-10 08:00", "2025-02-10 16:00"])
+            .beeps_in("kjeller", 1234567890, &["2025-02-11 09:00"])
+            .beeps(1234567891, &["2025-02-11 10:00", "2025-02-12 08:00"])
+            .user("ola", &[1234567890])
+            .user("kari", &[1234567891])
+            .build()
+    }
+
+    #[test]
+    fn json_has_one_object_per_user_per_day() {
+        let rows = json_rows(&visits(), None, None, None, None);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0],
+            json::object! {
+                date: "2025-02-10",
+                username: "ola",
+                first: "2025-02-10T08:00:00+01:00",
+                last: "2025-02-10T16:00:00+01:00",
+                duration_seconds: 28800,
+                beeps: 2,
+                coffee: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn tables_are_filtered() {
+        let conn = visits();
+        let date = |day| NaiveDate::from_ymd_opt(2025, 2, day).unwrap();
+        let days = |from, to, username, location| {
+            json_rows(&conn, from, to, username, location)
+                .members()
+                .map(|row| format!("{} {}", row["date"], row["username"]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            days(Some(date(11)), None, None, None),
+            ["2025-02-11 kari", "2025-02-11 ola", "2025-02-12 kari"]
+        );
+        assert_eq!(
+            days(None, Some(date(11)), None, None),
+            ["2025-02-10 ola", "2025-02-11 kari", "2025-02-11 ola"]
+        );
+        assert_eq!(
+            days(None, None, Some("kari"), None),
+            ["2025-02-11 kari", "2025-02-12 kari"]
+        );
+        assert_eq!(days(None, None, None, Some("kjeller")), ["2025-02-11 ola"]);
+    }
+
+    #[test]
+    fn ical_has_one_event_per_day_in_oslo_time() {
+        let conn = Fixture::new()
+            // Monday night until 04:59 still counts as monday
+            .beeps(1234567890, &["2025-02-10 08:00", "2025-02-11 04:30"])
+            .beeps(1234567891, &["2025-02-11 05:00", "2025-02-11 16:00"])
+            // Summer time
+            .beeps(1234567891, &["2025-03-31 09:00"])
+            .user("ola", &[1234567890, 1234567891])
+            .build();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("ola.ics");
+
+        export_ical(&conn, "ola", Some(output.clone())).unwrap();
+        let ical = fs::read_to_string(output).unwrap();
+        assert!(ical.ends_with("END:VCALENDAR\r\n"));
+        let lines: Vec<_> = ical.split("\r\n").collect();
+        let values = |key: &str| {
+            lines
+                .iter()
+                .filter_map(|line| line.strip_prefix(key))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("BEGIN:VEVENT").len(), 3);
+        assert_eq!(
+            values("DTSTART;TZID=Europe/Oslo:"),
+            ["20250210T080000", "20250211T050000", "20250331T090000"]
+        );
+        assert_eq!(
+            values("DTEND;TZID=Europe/Oslo:"),
+            ["20250211T043000", "20250211T160000", "20250331T090000"]
+        );
+        // Both cards are one user, named after the lowest card ID
+        assert_eq!(
+            values("UID:"),
+            [
+                "20250210-1234567890@sal",
+                "20250211-1234567890@sal",
+                "20250331-1234567890@sal"
+            ]
+        );
+        assert_eq!(values("DESCRIPTION:"), ["2 bip", "2 bip", "1 bip"]);
+    }
+
+    #[test]
+    fn database_errors_are_returned() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("ola.ics");
+        let err = export_ical(&conn, "ola", Some(output.clone())).unwrap_err();
+        assert!(err.to_string().contains("no such table"), "{err}");
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Europe::Oslo;
+use rusqlite::Connection;
+
+use crate::clock::FixedClock;
+use crate::migrate::create_tables;
+use crate::models::{open_db, rollover_date, Person, DEFAULT_LOCATION};
+
+/// A time on the wall clock in Oslo, written `YYYY-MM-DD HH:MM`
+pub fn oslo(time: &str) -> DateTime<Utc> {
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")
+        .unwrap()
+        .and_local_timezone(Oslo)
+        .unwrap()
+        .to_utc()
+}
+
+/// `HH:MM` on the current day, for tests of occupancy and the dashboard
+pub fn today(time: &str) -> String {
+    format!("{} {time}", rollover_date(Utc::now()))
+}
+
+/// Builds an in-memory database with users and beeps
+pub struct Fixture {
+    conn: Connection,
+    clock: FixedClock,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let conn = open_db(Path::new(":memory:"));
+        create_tables(&conn).unwrap();
+        Self {
+            conn,
+            clock: FixedClock::new(Utc::now()),
+        }
+    }
+
+    /// Beeps `card` in the default room at each time in Oslo
+    pub fn beeps(self, card: u32, times: &[&str]) -> Self {
+        self.beeps_in(DEFAULT_LOCATION, card, times)
+    }
+
+    pub fn beeps_in(self, location: &str, card: u32, times: &[&str]) -> Self {
+        for time in times {
+            self.clock.set(oslo(time));
+            Person::register(&self.conn, &self.clock, card, location).unwrap();
+        }
+        self
+    }
+
+    /// Registers every card in `cards` on `username`
+    pub fn user(self, username: &str, cards: &[u32]) -> Self {
+        for card in cards {
+            self.conn
+                .execute(
+                    "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3)",
+                    (card, username, Utc::now()),
+                )
+                .unwrap();
+        }
+        self
+    }
+
+    pub fn build(self) -> Connection {
+        self.conn
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, Utc, Weekday};
+use chrono_tz::Europe::Oslo;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use rusqlite::Connection;
+
+use crate::migrate::create_tables;
+use crate::models::{open_db, rollover_date, DEFAULT_LOCATION};
+use crate::pseudonym::card_id;
+
+/// How the generated users behave
+#[derive(Debug, Clone)]
+pub struct Behaviour {
+    /// Usual arrival time, and how far apart the usual times of different users are
+    pub arrival: NaiveTime,
+    pub arrival_spread: TimeDelta,
+    /// Usual time between arrival and departure, and how much it varies from day to day
+    pub stay: TimeDelta,
+    pub stay_spread: TimeDelta,
+    /// Chance of coming on a weekday and on a weekend day
+    pub weekday_attendance: f64,
+    pub weekend_attendance: f64,
+    /// Chance of a beep being registered twice
+    pub double_beeps: f64,
+    /// Chance of leaving without beeping out
+    pub forgotten_checkouts: f64,
+}
+
+/// Day to day variation in the arrival of a single user
+const ARRIVAL_NOISE: TimeDelta = TimeDelta::minutes(30);
+
+/// Share of beeps made with the second number of a card
+const SECOND_NUMBER: f64 = 0.2;
+
+/// A generated user with the two numbers of their card
+struct User {
+    username: String,
+    cards: [u32; 2],
+    arrival: Normal<f64>,
+}
+
+/// Minutes after midnight
+fn minutes(time: NaiveTime) -> f64 {
+    (time - NaiveTime::MIN).num_seconds() as f64 / 60.0
+}
+
+/// Card numbers are ten digits, which fit in a `u32` up to 4294967295
+fn new_card(rng: &mut StdRng, taken: &mut HashSet<u32>) -> u32 {
+    loop {
+        let card = rng.gen_range(1_000_000_000..=u32::MAX);
+        if taken.insert(card) {
+            return card;
+        }
+    }
+}
+
+/// The time on `date` in Oslo `minutes` after midnight, or `None` if the clocks skip it
+fn at(date: NaiveDate, minutes: f64) -> Option<DateTime<Utc>> {
+    let time = date.and_time(NaiveTime::MIN) + TimeDelta::seconds((minutes * 60.0) as i64);
+    time.and_local_timezone(Oslo)
+        .earliest()
+        .map(|time| time.to_utc())
+}
+
+/// Fills `conn` with `users` users beeping on every day from `from` to `to` like `behaviour`,
+/// returning the number of beeps
+pub fn fill(
+    conn: &Connection,
+    users: usize,
+    from: NaiveDate,
+    to: NaiveDate,
+    behaviour: &Behaviour,
+    rng: &mut StdRng,
+) -> rusqlite::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let usual_arrival = Normal::new(
+        minutes(behaviour.arrival),
+        behaviour.arrival_spread.num_minutes() as f64,
+    )
+    .unwrap();
+    let stay = Normal::new(
+        behaviour.stay.num_minutes() as f64,
+        behaviour.stay_spread.num_minutes() as f64,
+    )
+    .unwrap();
+
+    let mut taken = HashSet::new();
+    let users = (0..users)
+        .map(|i| User {
+            username: format!("bruker{:04}", i + 1),
+            cards: [new_card(rng, &mut taken), new_card(rng, &mut taken)],
+            arrival: Normal::new(
+                usual_arrival.sample(rng),
+                ARRIVAL_NOISE.num_minutes() as f64,
+            )
+            .unwrap(),
+        })
+        .collect::<Vec<_>>();
+    for user in &users {
+        for card in user.cards {
+            tx.execute(
+                "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3)",
+                (card_id(card.into()), &user.username, Utc::now()),
+            )?;
+        }
+    }
+
+    let mut insert = tx.prepare(
+        "INSERT OR IGNORE INTO logs (id, timestamp, date, location) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    let mut beeps = 0;
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        let attendance = match date.weekday() {
+            Weekday::Sat | Weekday::Sun => behaviour.weekend_attendance,
+            _ => behaviour.weekday_attendance,
+        };
+        for user in &users {
+            if !rng.gen_bool(attendance) {
+                continue;
+            }
+            // Days end at 04:59 the next morning
+            let arrival = user.arrival.sample(rng).clamp(5.0 * 60.0, 28.0 * 60.0);
+            let departure = (arrival + stay.sample(rng).max(10.0)).min(29.0 * 60.0 - 1.0);
+            let mut times = vec![arrival];
+            if !rng.gen_bool(behaviour.forgotten_checkouts) {
+                times.push(departure);
+            }
+            for minutes in times {
+                let Some(timestamp) = at(date, minutes) else {
+                    continue;
+                };
+                let card = user.cards[usize::from(rng.gen_bool(SECOND_NUMBER))];
+                let mut timestamps = vec![timestamp];
+                if rng.gen_bool(behaviour.double_beeps) {
+                    timestamps.push(timestamp + TimeDelta::seconds(rng.gen_range(1..5)));
+                }
+                for timestamp in timestamps {
+                    beeps += insert.execute((
+                        card_id(card.into()),
+                        timestamp,
+                        rollover_date(timestamp),
+                        DEFAULT_LOCATION,
+                    ))?;
+                }
+            }
+        }
+    }
+    drop(insert);
+    tx.commit()?;
+    Ok(beeps)
+}
+
+/// Writes `users` users with `years` years of beeps to a new database at `db`
+pub fn generate(
+    db: &Path,
+    users: usize,
+    years: u32,
+    behaviour: &Behaviour,
+    seed: Option<u64>,
+) -> io::Result<()> {
+    if db.exists() {
+        return Err(io::Error::other(format!(
+            "{} already exists, generate into a new database",
+            db.display()
+        )));
+    }
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let to = rollover_date(Utc::now());
+    let from = to - TimeDelta::days(365 * i64::from(years));
+    let beeps = fill(&conn, users, from, to, behaviour, &mut rng).map_err(io::Error::other)?;
+    println!(
+        "Wrote {users} users with {beeps} beeps from {from} to {to} to {}",
+        db.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime, TimeDelta};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{fill, Behaviour};
+    use crate::fixtures::Fixture;
+    use crate::models::{get_days, get_users, ids_for_username};
+
+    fn behaviour() -> Behaviour {
+        Behaviour {
+            arrival: NaiveTime::from_hms_opt(8, 30, 0).unwrap(),
+            arrival_spread: TimeDelta::hours(1),
+            stay: TimeDelta::hours(7),
+            stay_spread: TimeDelta::hours(2),
+            weekday_attendance: 1.0,
+            weekend_attendance: 0.0,
+            double_beeps: 0.0,
+            forgotten_checkouts: 0.0,
+        }
+    }
+
+    #[test]
+    fn users_beep_in_and_out_on_weekdays() {
+        let conn = Fixture::new().build();
+        // Monday to sunday
+        let from = NaiveDate::from_ymd_opt(2025, 2, 10).unwrap();
+        let to = NaiveDate::from_ymd_opt(2025, 2, 16).unwrap();
+        let beeps = fill(
+            &conn,
+            3,
+            from,
+            to,
+            &behaviour(),
+            &mut StdRng::seed_from_u64(1),
+        );
+        assert_eq!(beeps.unwrap(), 3 * 5 * 2);
+
+        let users = get_users(&conn);
+        assert_eq!(users.len(), 3);
+        assert_eq!(users[0].0, "bruker0001");
+        let ids = ids_for_username(&conn, "bruker0001").unwrap();
+        assert_eq!(ids.len(), 2);
+        let days = get_days(&conn, &ids, None).unwrap();
+        assert_eq!(days.len(), 5);
+        assert!(days
+            .iter()
+            .all(|day| day.beeps == 2 && day.span() >= TimeDelta::minutes(10)));
+
+        // Every beep is noise when they always forget to beep out and always beep twice
+        let conn = Fixture::new().build();
+        let noisy = Behaviour {
+            double_beeps: 1.0,
+            forgotten_checkouts: 1.0,
+            ..behaviour()
+        };
+        let beeps = fill(&conn, 3, from, to, &noisy, &mut StdRng::seed_from_u64(1));
+        assert_eq!(beeps.unwrap(), 3 * 5 * 2);
+        let ids = ids_for_username(&conn, "bruker0002").unwrap();
+        let days = get_days(&conn, &ids, None).unwrap();
+        assert!(days.iter().all(|day| day.span() < TimeDelta::seconds(5)));
+    }
+}
@@ -1,6 +1,6 @@
-use chrono::{Datelike, TimeDelta, Utc};
-use chrono_tz::Europe::Oslo;
-use itertools::Itertools;
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDate, TimeDelta, Timelike};
 use ratatui::{
     buffer::Buffer,
     layout::Rect,
@@ -9,37 +9,388 @@ use ratatui::{
     widgets::{Block, Widget},
 };
 
+use crate::models::{calendar, Day, DayOrDate};
+
 pub struct GithubMap<'a> {
-    values: &'a [Option<u64>],
+    /// Registered days, newest first
+    days: &'a [Day],
+    scale: &'a ColorScale,
+    /// Latest date drawn, placed in the rightmost column
+    today: NaiveDate,
 }
 
-impl<'a> GithubMap<'a> {
-    pub fn new(day_fractions: &'a [Option<u64>]) -> Self {
+/// What each square of the map encodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// Minutes between first and last beep
+    Duration,
+    /// Minutes after midnight of the first beep. Beeps after midnight count past 24:00
+    Arrival,
+    /// Number of beeps
+    Beeps,
+    /// Number of coffees
+    Coffee,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [
+        Metric::Duration,
+        Metric::Arrival,
+        Metric::Beeps,
+        Metric::Coffee,
+    ];
+
+    /// Name used in `sal.json`
+    pub fn key(&self) -> &'static str {
+        match self {
+            Metric::Duration => "duration",
+            Metric::Arrival => "arrival",
+            Metric::Beeps => "beeps",
+            Metric::Coffee => "coffee",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Metric::Duration => "Tid på sal",
+            Metric::Arrival => "Ankomsttid",
+            Metric::Beeps => "Antall bip",
+            Metric::Coffee => "Antall kaffe",
+        }
+    }
+
+    pub fn value(&self, day: &Day) -> u64 {
+        match self {
+            Metric::Duration => day.span().num_minutes() as u64,
+            Metric::Arrival => {
+                let time = day.start.time() - TimeDelta::hours(5);
+                (time.hour() * 60 + time.minute()) as u64 + 5 * 60
+            }
+            Metric::Beeps => day.beeps as u64,
+            Metric::Coffee => day.coffee as u64,
+        }
+    }
+
+    pub fn default_thresholds(&self) -> Vec<u64> {
+        match self {
+            Metric::Duration => vec![60, 2 * 60, 4 * 60, 8 * 60, 10 * 60, 12 * 60],
+            Metric::Arrival => vec![7 * 60, 8 * 60, 9 * 60, 10 * 60, 12 * 60, 15 * 60],
+            Metric::Beeps => vec![2, 3, 4, 6, 8, 10],
+            Metric::Coffee => vec![1, 2, 3, 4, 5, 6],
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        let i = Self::ALL.iter().position(|m| m == self).unwrap();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+
+    pub fn prev(&self) -> Self {
+        let i = Self::ALL.iter().position(|m| m == self).unwrap();
+        Self::ALL[(i + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+
+    fn format(&self, value: u64) -> String {
+        match self {
+            Metric::Duration if value.is_multiple_of(60) => format!("{}", value / 60),
+            Metric::Duration => format!("{}:{:02}", value / 60, value % 60),
+            Metric::Arrival => format!("{:02}:{:02}", (value / 60) % 24, value % 60),
+            Metric::Beeps | Metric::Coffee => value.to_string(),
+        }
+    }
+
+    /// Unit following `value`
+    fn unit(&self, value: u64) -> &'static str {
+        match self {
+            Metric::Duration if value == 60 => " time",
+            Metric::Duration => " timer",
+            Metric::Arrival => "",
+            Metric::Beeps => " bip",
+            Metric::Coffee => " kaffe",
+        }
+    }
+
+    /// Legend text for values in `low..high`
+    fn describe(&self, low: Option<u64>, high: Option<u64>) -> String {
+        match (low, high) {
+            (None, Some(high)) if *self == Metric::Arrival => format!("Før {}", self.format(high)),
+            (None, Some(high)) => format!("Under {}{}", self.format(high), self.unit(high)),
+            (Some(low), None) if *self == Metric::Arrival => format!("Etter {}", self.format(low)),
+            (Some(low), None) => format!("Minst {}{}", self.format(low), self.unit(low)),
+            (Some(low), Some(high)) => {
+                format!(
+                    "{}-{}{}",
+                    self.format(low),
+                    self.format(high),
+                    self.unit(high)
+                )
+            }
+            (None, None) => "Alle".to_string(),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|metric| metric.key() == s)
+            .ok_or_else(|| {
+                format!("Unknown metric {s:?}, expected one of duration, arrival, beeps, coffee")
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Palette {
+    /// Blue for short visits, greens for a normal day, reds for very long ones
+    Standard,
+    /// Viridis, distinguishable with the common forms of colour blindness
+    ColorBlind,
+    Custom(Vec<Color>),
+}
+
+impl Palette {
+    /// Colours from lowest to highest bucket.
+    /// Without truecolor, colours are limited to the 16 ANSI colours.
+    pub fn colors(&self, truecolor: bool) -> Vec<Color> {
+        match (self, truecolor) {
+            (Palette::Standard, true) => vec![
+                Color::LightBlue,
+                Color::Rgb(0, 240, 0),
+                Color::Rgb(0, 180, 0),
+                Color::Rgb(0, 120, 0),
+                Color::Rgb(0, 60, 0),
+                Color::Rgb(180, 0, 0),
+                Color::Rgb(60, 0, 0),
+            ],
+            (Palette::Standard, false) => vec![
+                Color::LightBlue,
+                Color::LightGreen,
+                Color::Green,
+                Color::Cyan,
+                Color::Yellow,
+                Color::LightRed,
+                Color::Red,
+            ],
+            (Palette::ColorBlind, true) => vec![
+                Color::Rgb(0x44, 0x01, 0x54),
+                Color::Rgb(0x44, 0x39, 0x83),
+                Color::Rgb(0x31, 0x68, 0x8e),
+                Color::Rgb(0x21, 0x91, 0x8c),
+                Color::Rgb(0x35, 0xb7, 0x79),
+                Color::Rgb(0x90, 0xd7, 0x43),
+                Color::Rgb(0xfd, 0xe7, 0x25),
+            ],
+            (Palette::ColorBlind, false) => vec![
+                Color::Magenta,
+                Color::Blue,
+                Color::LightBlue,
+                Color::Cyan,
+                Color::LightCyan,
+                Color::Yellow,
+                Color::LightYellow,
+            ],
+            (Palette::Custom(colors), true) => colors.clone(),
+            (Palette::Custom(colors), false) => colors.iter().map(|c| to_ansi16(*c)).collect(),
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Palette::Standard),
+            "colorblind" => Ok(Palette::ColorBlind),
+            _ => Err(format!(
+                "Unknown palette {s:?}, expected standard, colorblind or a list of colours"
+            )),
+        }
+    }
+}
+
+/// Whether the terminal advertises 24-bit colour support
+pub fn supports_truecolor() -> bool {
+    std::env::var("COLORTERM").is_ok_and(|val| val == "truecolor" || val == "24bit")
+}
+
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (128, 0, 0)),
+    (Color::Green, (0, 128, 0)),
+    (Color::Yellow, (128, 128, 0)),
+    (Color::Blue, (0, 0, 128)),
+    (Color::Magenta, (128, 0, 128)),
+    (Color::Cyan, (0, 128, 128)),
+    (Color::Gray, (192, 192, 192)),
+    (Color::DarkGray, (128, 128, 128)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (0, 0, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// Hex notation of a colour, for use in HTML and SVG
+pub fn css_color(color: Color) -> String {
+    let (r, g, b) = match color {
+        Color::Rgb(r, g, b) => (r, g, b),
+        _ => ANSI16
+            .iter()
+            .find(|(ansi, _)| *ansi == color)
+            .map(|(_, rgb)| *rgb)
+            .unwrap_or((128, 128, 128)),
+    };
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Closest of the 16 ANSI colours. Non-RGB colours are returned unchanged.
+fn to_ansi16(color: Color) -> Color {
+    let Color::Rgb(r, g, b) = color else {
+        return color;
+    };
+    let dist = |(r2, g2, b2): (u8, u8, u8)| {
+        let dr = r as i32 - r2 as i32;
+        let dg = g as i32 - g2 as i32;
+        let db = b as i32 - b2 as i32;
+        dr * dr + dg * dg + db * db
+    };
+    ANSI16
+        .iter()
+        .min_by_key(|(_, rgb)| dist(*rgb))
+        .map(|(color, _)| *color)
+        .unwrap()
+}
+
+/// Maps metric values to colours.
+/// A value falls in bucket `i` if it is below `thresholds[i]` and not below any earlier threshold.
+#[derive(Debug, Clone)]
+pub struct ColorScale {
+    metric: Metric,
+    thresholds: Vec<u64>,
+    colors: Vec<Color>,
+    empty: Color,
+}
+
+impl ColorScale {
+    pub fn new(metric: Metric, thresholds: Vec<u64>, palette: &Palette, truecolor: bool) -> Self {
+        let palette = palette.colors(truecolor);
+        let n_buckets = thresholds.len() + 1;
+        // Spread the palette over the buckets if the sizes do not match
+        let colors = (0..n_buckets)
+            .map(|i| match n_buckets {
+                1 => palette[0],
+                _ => palette[i * (palette.len() - 1) / (n_buckets - 1)],
+            })
+            .collect();
+
         Self {
-            values: day_fractions,
+            metric,
+            thresholds,
+            colors,
+            empty: Color::DarkGray,
+        }
+    }
+
+    pub fn color(&self, value: Option<u64>) -> Color {
+        match value {
+            None => self.empty,
+            Some(value) => {
+                let bucket = self.thresholds.iter().take_while(|t| value >= **t).count();
+                self.colors[bucket]
+            }
         }
     }
+
+    /// Legend text and colour of each bucket, starting with days without attendance
+    pub fn buckets(&self) -> Vec<(String, Color)> {
+        let lows = std::iter::once(None).chain(self.thresholds.iter().copied().map(Some));
+        let highs = self
+            .thresholds
+            .iter()
+            .copied()
+            .map(Some)
+            .chain(std::iter::once(None));
+
+        let buckets = lows
+            .zip(highs)
+            .zip(&self.colors)
+            .map(|((low, high), color)| (self.metric.describe(low, high), *color));
+
+        std::iter::once(("Ingen oppmøte".to_string(), self.empty))
+            .chain(buckets)
+            .collect()
+    }
+
+    pub fn legend(&self) -> Vec<Span<'static>> {
+        self.buckets()
+            .into_iter()
+            .map(|(text, color)| format!(" {text} ").set_style(color))
+            .collect()
+    }
 }
 
-const MS_IN_HOUR: u64 = 60 * 60 * 1000;
-const MS_IN_2_HOURS: u64 = MS_IN_HOUR * 2;
-const MS_IN_4_HOURS: u64 = MS_IN_HOUR * 4;
-const MS_IN_8_HOURS: u64 = MS_IN_HOUR * 8;
-const MS_IN_10_HOURS: u64 = MS_IN_HOUR * 10;
-const MS_IN_12_HOURS: u64 = MS_IN_HOUR * 12;
+/// A square of the map, counted from the rightmost column and the top row
+struct Square {
+    weeks_back: u16,
+    weekday: u16,
+    color: Color,
+}
 
-fn ms_to_color(milliseconds: Option<u64>) -> Color {
-    match milliseconds {
-        None => Color::DarkGray,
-        Some(milliseconds) => match milliseconds {
-            0..MS_IN_HOUR => Color::LightBlue,
-            0..MS_IN_2_HOURS => Color::Rgb(0, 240, 0),
-            0..MS_IN_4_HOURS => Color::Rgb(0, 180, 0),
-            0..MS_IN_8_HOURS => Color::Rgb(0, 120, 0),
-            0..MS_IN_10_HOURS => Color::Rgb(0, 60, 0),
-            0..MS_IN_12_HOURS => Color::Rgb(180, 0, 0),
-            _ => Color::Rgb(60, 0, 0),
-        },
+impl<'a> GithubMap<'a> {
+    pub fn new(days: &'a [Day], scale: &'a ColorScale, today: NaiveDate) -> Self {
+        Self { days, scale, today }
+    }
+
+    /// Squares of the last `n_weeks` weeks, newest first
+    fn squares(&self, n_weeks: u16) -> Vec<Square> {
+        let this_monday = monday_of(self.today);
+        // Walk backwards from today, so days without data up to today are drawn as absent
+        calendar(self.days, self.today)
+            .map(|day| {
+                let weeks_back = (this_monday - monday_of(day.date())).num_days() / 7;
+                let value = match day {
+                    DayOrDate::Registered(day) => Some(self.scale.metric.value(&day)),
+                    DayOrDate::Unregistered(_) => None,
+                };
+                (weeks_back, day.date(), value)
+            })
+            .take_while(|(weeks_back, _, _)| *weeks_back < n_weeks as i64)
+            .map(|(weeks_back, date, value)| Square {
+                weeks_back: weeks_back as u16,
+                weekday: date.weekday().num_days_from_monday() as u16,
+                color: self.scale.color(value),
+            })
+            .collect()
+    }
+
+    /// The map as a standalone SVG image, for the web dashboard
+    pub fn to_svg(&self, n_weeks: u16) -> String {
+        let size = 14;
+        let gap = 3;
+        let step = size + gap;
+        let width = step * n_weeks - gap;
+        let height = step * 7 - gap;
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {height}\" width=\"{width}\" height=\"{height}\">"
+        );
+        for square in self.squares(n_weeks) {
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{size}\" height=\"{size}\" rx=\"2\" fill=\"{}\"/>",
+                width + gap - step * (square.weeks_back + 1),
+                step * square.weekday,
+                css_color(square.color),
+            ));
+        }
+        svg.push_str("</svg>");
+        svg
     }
 }
 
@@ -47,28 +398,19 @@ impl<'a> Widget for GithubMap<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let width = 6;
         let height = 3;
+        let n_weeks = area.width / width;
 
-        let cols = (1..)
-            .map(|i| area.x + area.width - width * i)
-            .take_while_inclusive(|col| *col > width);
+        for square in self.squares(n_weeks) {
+            let square_area = Rect {
+                x: area.right() - width * (square.weeks_back + 1),
+                y: area.y + height * square.weekday,
+                width,
+                height,
+            };
+            if square_area.bottom() > area.bottom() {
+                continue;
+            }
 
-        let rows = (0..7).map(|i| area.y + height * i).rev();
-        let squares = cols.cartesian_product(rows).map(|(col, row)| Rect {
-            x: col,
-            y: row,
-            width,
-            height,
-        });
-
-        let colors = self.values.iter().map(|val| ms_to_color(*val));
-
-        let days_to_skip = 7
-            - (Utc::now() - TimeDelta::hours(5))
-                .with_timezone(&Oslo)
-                .weekday()
-                .num_days_from_sunday();
-
-        for (color, square) in colors.zip(squares.skip(days_to_skip as usize)) {
             Block::bordered()
                 .border_style(
                     Style::default()
@@ -76,24 +418,229 @@ impl<'a> Widget for GithubMap<'a> {
                         .fg(Color::Rgb(210, 210, 210)),
                 )
                 .border_type(ratatui::widgets::BorderType::QuadrantInside)
-                .style(Style::default().bg(color))
-                .render(square, buf);
+                .style(Style::default().bg(square.color))
+                .render(square_area, buf);
         }
     }
 }
 
-pub fn github_map_instructions() -> Vec<Span<'static>> {
-    let colors = [
-        (None, " Ingen oppmøte "),
-        (Some(0), " Mindre enn en time/bare ett bip "),
-        (Some(MS_IN_HOUR), " 1-2 timer "),
-        (Some(MS_IN_2_HOURS), " 2-4 timer "),
-        (Some(MS_IN_4_HOURS), " 4-8 timer "),
-        (Some(MS_IN_8_HOURS), " 8-10 timer "),
-        (Some(MS_IN_10_HOURS), " 10-12 timer "),
-        (Some(MS_IN_12_HOURS), " Over tolv timer "),
-    ]
-    .map(|(time, text)| text.set_style(ms_to_color(time)));
+fn monday_of(date: NaiveDate) -> NaiveDate {
+    date - TimeDelta::days(date.weekday().num_days_from_monday() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+
+    use super::{to_ansi16, ColorScale, GithubMap, Metric, Palette};
+    use crate::models::{rollover_date, Day};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    /// A registered day from 09:00 to `hours` later, in UTC
+    fn day(date: NaiveDate, hours: i64) -> Day {
+        let start = Utc.from_utc_datetime(&date.and_hms_opt(9, 0, 0).unwrap());
+        let end = start + chrono::TimeDelta::hours(hours);
+        Day::new(date, start, end, 2, 0)
+    }
+
+    fn scale() -> ColorScale {
+        ColorScale::new(
+            Metric::Duration,
+            vec![60, 4 * 60],
+            &Palette::Standard,
+            false,
+        )
+    }
+
+    fn color_of(hours: u64) -> Color {
+        scale().color(Some(hours * 60))
+    }
+
+    /// Background colour inside the square `weeks_back` columns from the right
+    fn color_at(buf: &Buffer, weeks_back: u16, weekday: u16) -> Color {
+        let area = buf.area;
+        let x = area.right() - 6 * (weeks_back + 1) + 2;
+        let y = area.y + 3 * weekday + 1;
+        buf[(x, y)].bg
+    }
+
+    fn render(days: &[Day], today: NaiveDate) -> Buffer {
+        let scale = scale();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 6 * 4, 3 * 7));
+        GithubMap::new(days, &scale, today).render(buf.area, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn squares_are_placed_by_weekday() {
+        // Thursday
+        let today = date(2025, 2, 6);
+        let buf = render(&[day(today, 2), day(date(2025, 2, 4), 8)], today);
+
+        assert_eq!(color_at(&buf, 0, 3), color_of(2));
+        assert_eq!(color_at(&buf, 0, 1), color_of(8));
+        // No data for wednesday
+        assert_eq!(color_at(&buf, 0, 2), Color::DarkGray);
+        // Friday has not happened yet
+        assert_eq!(color_at(&buf, 0, 4), Color::Reset);
+    }
+
+    #[test]
+    fn days_since_last_visit_are_padded() {
+        // Last visit on a tuesday, today is the monday two weeks later
+        let last_visit = date(2025, 2, 4);
+        let today = date(2025, 2, 17);
+        let buf = render(&[day(last_visit, 2)], today);
+
+        assert_eq!(color_at(&buf, 0, 0), Color::DarkGray);
+        assert_eq!(color_at(&buf, 1, 6), Color::DarkGray);
+        assert_eq!(color_at(&buf, 2, 1), color_of(2));
+        // Nothing before the first visit
+        assert_eq!(color_at(&buf, 2, 0), Color::Reset);
+    }
+
+    #[test]
+    fn gaps_between_visits_are_empty() {
+        let today = date(2025, 2, 7);
+        let days = [day(today, 2), day(date(2025, 2, 4), 2)];
+        let buf = render(&days, today);
+
+        assert_eq!(color_at(&buf, 0, 4), color_of(2));
+        assert_eq!(color_at(&buf, 0, 3), Color::DarkGray);
+        assert_eq!(color_at(&buf, 0, 2), Color::DarkGray);
+        assert_eq!(color_at(&buf, 0, 1), color_of(2));
+    }
+
+    #[test]
+    fn sunday_ends_the_column() {
+        let today = date(2025, 2, 9);
+        let buf = render(&[day(today, 2), day(date(2025, 2, 3), 2)], today);
+
+        assert_eq!(color_at(&buf, 0, 6), color_of(2));
+        assert_eq!(color_at(&buf, 0, 0), color_of(2));
+    }
 
-    colors.to_vec()
+    #[test]
+    fn today_rolls_over_at_five() {
+        // 04:30 on a tuesday in Oslo still counts as monday
+        let before = Utc.with_ymd_and_hms(2025, 2, 11, 3, 30, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2025, 2, 11, 4, 0, 0).unwrap();
+        assert_eq!(rollover_date(before), date(2025, 2, 10));
+        assert_eq!(rollover_date(after), date(2025, 2, 11));
+
+        let monday = date(2025, 2, 10);
+        let buf = render(&[day(monday, 2)], rollover_date(before));
+        assert_eq!(color_at(&buf, 0, 0), color_of(2));
+        assert_eq!(color_at(&buf, 0, 1), Color::Reset);
+
+        let buf = render(&[day(monday, 2)], rollover_date(after));
+        assert_eq!(color_at(&buf, 0, 1), Color::DarkGray);
+    }
+
+    #[test]
+    fn svg_places_squares_like_the_terminal() {
+        // Thursday
+        let today = date(2025, 2, 6);
+        let days = [day(today, 2), day(date(2025, 1, 28), 8)];
+        let scale = scale();
+        let svg = GithubMap::new(&days, &scale, today).to_svg(2);
+
+        // Ten days from the tuesday last week until today
+        assert_eq!(svg.matches("<rect").count(), 10);
+        // Today in the rightmost column, fourth row
+        assert!(
+            svg.contains(r##"<rect x="17" y="51" width="14" height="14" rx="2" fill="#008080"/>"##)
+        );
+        // The tuesday last week in the leftmost column, second row
+        assert!(
+            svg.contains(r##"<rect x="0" y="17" width="14" height="14" rx="2" fill="#800000"/>"##)
+        );
+    }
+
+    #[test]
+    fn thresholds_start_the_next_bucket() {
+        let scale = scale();
+        assert_eq!(scale.color(None), Color::DarkGray);
+        assert_eq!(scale.color(Some(0)), Color::LightBlue);
+        assert_eq!(scale.color(Some(59)), Color::LightBlue);
+        assert_eq!(scale.color(Some(60)), Color::Cyan);
+        assert_eq!(scale.color(Some(4 * 60 - 1)), Color::Cyan);
+        assert_eq!(scale.color(Some(4 * 60)), Color::Red);
+        assert_eq!(scale.color(Some(u64::MAX)), Color::Red);
+    }
+
+    #[test]
+    fn colors_without_truecolor_are_the_closest_ansi_color() {
+        assert_eq!(to_ansi16(Color::Rgb(0, 240, 0)), Color::LightGreen);
+        assert_eq!(to_ansi16(Color::Rgb(0, 120, 0)), Color::Green);
+        assert_eq!(to_ansi16(Color::Rgb(100, 100, 100)), Color::DarkGray);
+        assert_eq!(to_ansi16(Color::Rgb(200, 200, 200)), Color::Gray);
+        assert_eq!(to_ansi16(Color::Indexed(42)), Color::Indexed(42));
+
+        let custom = Palette::Custom(vec![Color::Rgb(250, 10, 10), Color::Rgb(10, 10, 120)]);
+        assert_eq!(custom.colors(false), [Color::LightRed, Color::Blue]);
+        assert_eq!(
+            custom.colors(true),
+            [Color::Rgb(250, 10, 10), Color::Rgb(10, 10, 120)]
+        );
+    }
+
+    #[test]
+    fn the_colorblind_palette_spans_viridis() {
+        let scale = ColorScale::new(
+            Metric::Duration,
+            vec![60, 4 * 60],
+            &Palette::ColorBlind,
+            true,
+        );
+        assert_eq!(scale.color(Some(0)), Color::Rgb(0x44, 0x01, 0x54));
+        assert_eq!(scale.color(Some(60)), Color::Rgb(0x21, 0x91, 0x8c));
+        assert_eq!(scale.color(Some(4 * 60)), Color::Rgb(0xfd, 0xe7, 0x25));
+    }
+
+    #[test]
+    fn the_legend_describes_each_bucket() {
+        let buckets = scale().buckets();
+        assert_eq!(
+            buckets,
+            [
+                ("Ingen oppmøte".to_string(), Color::DarkGray),
+                ("Under 1 time".to_string(), Color::LightBlue),
+                ("1-4 timer".to_string(), Color::Cyan),
+                ("Minst 4 timer".to_string(), Color::Red),
+            ]
+        );
+        // One hour is singular
+        let scale = ColorScale::new(Metric::Duration, vec![30, 60], &Palette::Standard, false);
+        let texts = scale
+            .buckets()
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [
+                "Ingen oppmøte",
+                "Under 0:30 timer",
+                "0:30-1 time",
+                "Minst 1 time"
+            ]
+        );
+        let scale = ColorScale::new(
+            Metric::Arrival,
+            vec![8 * 60 + 30],
+            &Palette::Standard,
+            false,
+        );
+        let texts = scale
+            .buckets()
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["Ingen oppmøte", "Før 08:30", "Etter 08:30"]);
+    }
 }
@@ -0,0 +1,626 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use arrow_array::{cast::AsArray, types::*, Array};
+use arrow_ipc::reader::FileReader;
+use arrow_schema::{DataType, TimeUnit};
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::migrate::create_tables;
+use crate::models::{get_db, id_array, ids_for_username, rollover_date, DEFAULT_LOCATION};
+use crate::pseudonym::{card_id, redact};
+
+/// What one pass over the prototype's files changed
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    /// Files with new content
+    pub files: usize,
+    pub inserted: usize,
+    pub duplicates: usize,
+    pub malformed: usize,
+    /// New or changed usernames
+    pub users: usize,
+}
+
+impl ImportSummary {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files read: {} beeps inserted, {} duplicates skipped, {} malformed lines quarantined, {} usernames updated",
+            self.files, self.inserted, self.duplicates, self.malformed, self.users
+        )
+    }
+}
+
+/// Imports everything the Python prototype has written since the last import.
+/// With `watch`, keeps polling for new data until interrupted.
+pub fn import(root: &Path, watch: Option<Duration>) -> io::Result<()> {
+    let mut conn = get_db();
+    create_tables(&conn)?;
+
+    loop {
+        let summary = import_once(&mut conn, root)?;
+        let Some(interval) = watch else {
+            println!("{summary}");
+            return Ok(());
+        };
+        if !summary.is_empty() {
+            println!("{} {summary}", Local::now().format("%F %T"));
+        }
+        thread::sleep(interval);
+    }
+}
+
+pub fn import_once(conn: &mut Connection, root: &Path) -> io::Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+
+    // Usernames first, so stats files can be matched with card IDs
+    import_users(conn, &root.join("users.json"), &mut summary)?;
+    for path in files_in(&root.join("logs"))? {
+        import_log(conn, &path, &mut summary)?;
+    }
+    for path in files_in(&root.join("stats"))? {
+        if path.extension().is_some_and(|ext| ext == "feather") {
+            import_stats(conn, &path, &mut summary)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+fn files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn import_users(conn: &mut Connection, path: &Path, summary: &mut ImportSummary) -> io::Result<()> {
+    if !path.is_file() || is_unchanged(conn, path)? {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(path)?;
+    let users = match json::parse(&contents) {
+        Ok(users) => users,
+        Err(err) => {
+            // Most likely caught while the prototype was writing it, try again next time
+            eprintln!("Skipping {}: {err}", path.display());
+            return Ok(());
+        }
+    };
+
+    let tx = conn.transaction().unwrap();
+    for (i, (userid, username)) in users.entries().enumerate() {
+        let (Ok(userid), Some(username)) = (userid.parse::<u64>(), username.as_str()) else {
+            let line = format!("{userid}: {username}");
+            quarantine(&tx, path, i + 1, &line, "Expected card ID and username");
+            summary.malformed += 1;
+            continue;
+        };
+        let userid = card_id(userid);
+
+        // Only apply usernames changed by the prototype, not ones changed in sal since
+        let previous: Option<String> = tx
+            .query_row(
+                "SELECT username FROM imported_users WHERE id = ?1",
+                (userid,),
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        if previous.as_deref() == Some(username) {
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3)
+            ON CONFLICT (id) DO UPDATE SET username=excluded.username, changed=excluded.changed",
+            (userid, username, Utc::now()),
+        )
+        .unwrap();
+        tx.execute(
+            "INSERT INTO imported_users (id, username) VALUES (?1, ?2)
+            ON CONFLICT (id) DO UPDATE SET username=excluded.username",
+            (userid, username),
+        )
+        .unwrap();
+        summary.users += 1;
+    }
+    mark_imported(&tx, path)?;
+    tx.commit().unwrap();
+    summary.files += 1;
+
+    Ok(())
+}
+
+/// Log files are only ever appended to, so continue from where the last import stopped
+fn import_log(conn: &mut Connection, path: &Path, summary: &mut ImportSummary) -> io::Result<()> {
+    let progress: Option<(u64, usize)> = conn
+        .query_row(
+            "SELECT offset, lines FROM imported_files WHERE path = ?1",
+            (path.to_string_lossy(),),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .unwrap();
+    let (mut offset, mut line_number) = progress.unwrap_or((0, 0));
+
+    let len = fs::metadata(path)?.len();
+    if len < offset {
+        eprintln!(
+            "{} is shorter than when last imported, reading it again",
+            path.display()
+        );
+        (offset, line_number) = (0, 0);
+    }
+    if len == offset {
+        return Ok(());
+    }
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    // The prototype might be in the middle of writing the last line, leave it for the next import
+    let Some(end) = buf.iter().rposition(|b| *b == b'\n') else {
+        return Ok(());
+    };
+
+    let tx = conn.transaction().unwrap();
+    for line in buf[..end].split(|b| *b == b'\n') {
+        line_number += 1;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_line(line) {
+            Ok((timestamp, id, location, beeps)) => {
+                let inserted = tx
+                    .execute(
+                        "INSERT OR IGNORE INTO logs (id, timestamp, date, location, beeps)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                        (
+                            card_id(id),
+                            timestamp,
+                            rollover_date(timestamp),
+                            location,
+                            beeps,
+                        ),
+                    )
+                    .unwrap();
+                match inserted {
+                    0 => summary.duplicates += 1,
+                    _ => summary.inserted += 1,
+                }
+            }
+            Err(err) => {
+                quarantine(&tx, path, line_number, line, &err);
+                summary.malformed += 1;
+            }
+        }
+    }
+    tx.execute(
+        "INSERT INTO imported_files (path, offset, lines) VALUES (?1, ?2, ?3)
+        ON CONFLICT (path) DO UPDATE SET offset=excluded.offset, lines=excluded.lines",
+        (path.to_string_lossy(), offset + end as u64 + 1, line_number),
+    )
+    .unwrap();
+    tx.commit().unwrap();
+    summary.files += 1;
+
+    Ok(())
+}
+
+/// Reads `timestamp,card ID[,location[,beeps]]`, where the location is written by `dump` for
+/// beeps outside the default room, and the number of beeps for days collapsed by `prune`
+fn parse_line(line: &str) -> Result<(DateTime<Utc>, u64, &str, u32), String> {
+    let mut parts = line.split(',').map(str::trim);
+    let (Some(timestamp), Some(id)) = (parts.next(), parts.next()) else {
+        return Err("Expected `timestamp,card ID`".to_string());
+    };
+    let timestamp = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|err| format!("Invalid timestamp: {err}"))?;
+    let id = id
+        .parse()
+        .map_err(|err| format!("Invalid card ID: {err}"))?;
+    let location = parts.next().unwrap_or(DEFAULT_LOCATION);
+    let beeps = parts
+        .next()
+        .map_or(Ok(1), str::parse)
+        .map_err(|err| format!("Invalid number of beeps: {err}"))?;
+    Ok((timestamp.to_utc(), id, location, beeps))
+}
+
+/// Stats files hold the first and last tap of each day per username.
+/// They are rewritten on every tap, so they are read whole whenever they change,
+/// and only taps missing from the logs are inserted.
+fn import_stats(conn: &mut Connection, path: &Path, summary: &mut ImportSummary) -> io::Result<()> {
+    if is_unchanged(conn, path)? {
+        return Ok(());
+    }
+    let username = path.file_stem().unwrap().to_string_lossy();
+    let ids = ids_for_username(conn, &username).unwrap();
+
+    let tx = conn.transaction().unwrap();
+    let taps = match (ids.is_empty(), read_stats_file(path)) {
+        (true, _) => Err(format!("No card ID is registered for {username}")),
+        (false, taps) => taps,
+    };
+    match taps {
+        Ok(taps) => {
+            let id = *ids.iter().min().unwrap();
+            let ids = id_array(&ids);
+            for timestamp in taps {
+                // The tap may be logged on any of the user's cards
+                let exists: bool = tx
+                    .query_row(
+                        "SELECT EXISTS (
+                            SELECT 1 FROM logs WHERE timestamp = ?1 AND id IN rarray(?2)
+                        )",
+                        (timestamp, ids.clone()),
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                if exists {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                tx.execute(
+                    "INSERT INTO logs (id, timestamp, date) VALUES (?1, ?2, ?3)",
+                    (id, timestamp, rollover_date(timestamp)),
+                )
+                .unwrap();
+                summary.inserted += 1;
+            }
+        }
+        Err(err) => {
+            // Not marked as imported, so the file is read again on the next import
+            quarantine(&tx, path, 0, "", &err);
+            tx.commit().unwrap();
+            summary.malformed += 1;
+            return Ok(());
+        }
+    }
+    tx.execute(
+        "DELETE FROM import_quarantine WHERE path = ?1 AND line_number = 0",
+        (path.to_string_lossy(),),
+    )
+    .unwrap();
+    mark_imported(&tx, path)?;
+    tx.commit().unwrap();
+    summary.files += 1;
+
+    Ok(())
+}
+
+/// Every first and last tap in a feather file written by the prototype
+fn read_stats_file(path: &Path) -> Result<Vec<DateTime<Utc>>, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let reader = FileReader::try_new(file, None).map_err(|err| err.to_string())?;
+
+    let mut taps = vec![];
+    for batch in reader {
+        let batch = batch.map_err(|err| err.to_string())?;
+        for column in ["first_tap_time", "last_tap_time"] {
+            let array = batch
+                .column_by_name(column)
+                .ok_or_else(|| format!("Missing column {column}"))?;
+            taps.extend(timestamps(array)?);
+        }
+    }
+    Ok(taps)
+}
+
+fn timestamps(array: &dyn Array) -> Result<Vec<DateTime<Utc>>, String> {
+    // Arrow stores timestamps as UTC regardless of the column's timezone
+    let nanos: Vec<Option<i64>> = match array.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => array
+            .as_primitive::<TimestampSecondType>()
+            .iter()
+            .map(|t| t.map(|t| t * 1_000_000_000))
+            .collect(),
+        DataType::Timestamp(TimeUnit::Millisecond, _) => array
+            .as_primitive::<TimestampMillisecondType>()
+            .iter()
+            .map(|t| t.map(|t| t * 1_000_000))
+            .collect(),
+        DataType::Timestamp(TimeUnit::Microsecond, _) => array
+            .as_primitive::<TimestampMicrosecondType>()
+            .iter()
+            .map(|t| t.map(|t| t * 1_000))
+            .collect(),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => array
+            .as_primitive::<TimestampNanosecondType>()
+            .iter()
+            .collect(),
+        other => return Err(format!("Expected timestamps, found {other}")),
+    };
+    Ok(nanos
+        .into_iter()
+        .flatten()
+        .map(DateTime::from_timestamp_nanos)
+        .collect())
+}
+
+/// Keeps a line that could not be imported, with card numbers replaced by their IDs
+fn quarantine(conn: &Connection, path: &Path, line_number: usize, line: &str, error: &str) {
+    let (line, id) = redact(line);
+    conn.execute(
+        "INSERT OR IGNORE INTO import_quarantine (path, line_number, line, error, id)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        (path.to_string_lossy(), line_number, line, error, id),
+    )
+    .unwrap();
+}
+
+/// Size and modification time, to tell if a file that is rewritten rather than appended has changed
+fn modified(path: &Path) -> io::Result<String> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!("{}:{}", metadata.len(), modified.as_nanos()))
+}
+
+fn is_unchanged(conn: &Connection, path: &Path) -> io::Result<bool> {
+    let previous: Option<Option<String>> = conn
+        .query_row(
+            "SELECT modified FROM imported_files WHERE path = ?1",
+            (path.to_string_lossy(),),
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap();
+    Ok(previous.flatten() == Some(modified(path)?))
+}
+
+fn mark_imported(conn: &Connection, path: &Path) -> io::Result<()> {
+    conn.execute(
+        "INSERT INTO imported_files (path, offset, lines, modified) VALUES (?1, 0, 0, ?2)
+        ON CONFLICT (path) DO UPDATE SET modified=excluded.modified",
+        (path.to_string_lossy(), modified(path)?),
+    )
+    .unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use arrow_array::{RecordBatch, TimestampMicrosecondArray};
+    use arrow_ipc::{writer::FileWriter, writer::IpcWriteOptions, CompressionType};
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use chrono::{DateTime, Utc};
+    use rusqlite::{vtab::array, Connection};
+    use tempfile::TempDir;
+
+    use super::{import_once, ImportSummary};
+    use crate::migrate::create_tables;
+
+    fn setup() -> (Connection, TempDir) {
+        let conn = Connection::open_in_memory().unwrap();
+        array::load_module(&conn).unwrap();
+        create_tables(&conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("logs")).unwrap();
+        (conn, dir)
+    }
+
+    fn append(path: &Path, contents: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn count(conn: &Connection, table: &str) -> usize {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn logs_are_imported_incrementally() {
+        let (mut conn, dir) = setup();
+        let log = dir.path().join("logs/20250204.log");
+        append(
+            &log,
+            "2025-02-04T08:01:00.123456+00:00,1234567890\n2025-02-04T09:00:00+00:00,1234567890\n2025-02-04T16:",
+        );
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.inserted, 2);
+        assert_eq!(count(&conn, "logs"), 2);
+
+        // The half written line is finished, and the prototype logged a beep twice
+        append(
+            &log,
+            "30:00+00:00,1234567890\n2025-02-04T16:30:00+00:00,1234567890\n",
+        );
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.inserted, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(count(&conn, "logs"), 3);
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary, ImportSummary::default());
+    }
+
+    #[test]
+    fn malformed_lines_are_quarantined() {
+        let (mut conn, dir) = setup();
+        append(
+            &dir.path().join("logs/20250204.log"),
+            "garbage\n2025-02-04T08:01:00+00:00,abc\n2025-02-04T09:00:00+00:00,1234567890\n",
+        );
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.inserted, 1);
+        assert_eq!(summary.malformed, 2);
+        let line_number: usize = conn
+            .query_row(
+                "SELECT line_number FROM import_quarantine WHERE line LIKE '%abc'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(line_number, 2);
+    }
+
+    #[test]
+    fn rooms_written_by_dump_are_kept() {
+        let (mut conn, dir) = setup();
+        append(
+            &dir.path().join("logs/20250204.log"),
+            "2025-02-04T08:01:00+00:00,1234567890\n2025-02-04T09:00:00+00:00,1234567890,kjeller\n\
+            2025-02-04T16:00:00+00:00,1234567890,sal,3\n",
+        );
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!((summary.inserted, summary.malformed), (3, 0));
+        let rows: Vec<(String, u32)> = conn
+            .prepare("SELECT location, beeps FROM logs ORDER BY timestamp")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let rows = rows
+            .iter()
+            .map(|(l, b)| (l.as_str(), *b))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [("sal", 1), ("kjeller", 1), ("sal", 3)]);
+    }
+
+    #[test]
+    fn usernames_are_only_updated_when_changed_in_the_prototype() {
+        let (mut conn, dir) = setup();
+        let users = dir.path().join("users.json");
+        fs::write(&users, r#"{"1234567890": "ola", "1111111111": "kari"}"#).unwrap();
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.users, 2);
+
+        // Renamed in sal, then the prototype renames someone else
+        conn.execute(
+            "UPDATE people SET username = 'ola2' WHERE id = 1234567890",
+            (),
+        )
+        .unwrap();
+        fs::write(&users, r#"{"1234567890": "ola", "1111111111": "kari2"}"#).unwrap();
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.users, 1);
+
+        let username: String = conn
+            .query_row(
+                "SELECT username FROM people WHERE id = 1234567890",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(username, "ola2");
+    }
+
+    fn micros(timestamp: &str) -> i64 {
+        DateTime::parse_from_rfc3339(timestamp)
+            .unwrap()
+            .timestamp_micros()
+    }
+
+    /// Writes a stats file with one day, like pandas does, with lz4 compression and Oslo timestamps
+    fn write_stats(path: &Path) {
+        let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("Europe/Oslo".into()));
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("first_tap_time", timestamp.clone(), true),
+            Field::new("last_tap_time", timestamp, true),
+        ]));
+        let first =
+            TimestampMicrosecondArray::from(vec![micros("2025-02-04T09:01:00.123456+01:00")])
+                .with_timezone("Europe/Oslo");
+        let last = TimestampMicrosecondArray::from(vec![micros("2025-02-04T17:30:00+01:00")])
+            .with_timezone("Europe/Oslo");
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(first), Arc::new(last)]).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = fs::File::create(path).unwrap();
+        let options = IpcWriteOptions::default()
+            .try_with_compression(Some(CompressionType::LZ4_FRAME))
+            .unwrap();
+        let mut writer = FileWriter::try_new_with_options(file, &schema, options).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn stats_files_fill_in_missing_taps() {
+        let (mut conn, dir) = setup();
+        fs::write(
+            dir.path().join("users.json"),
+            r#"{"1234567890": "ola", "1234567891": "ola"}"#,
+        )
+        .unwrap();
+        append(
+            &dir.path().join("logs/20250204.log"),
+            "2025-02-04T08:01:00.123456+00:00,1234567891\n",
+        );
+
+        write_stats(&dir.path().join("stats/ola.feather"));
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary.inserted, 2);
+        assert_eq!(summary.duplicates, 1);
+
+        let last: DateTime<Utc> = conn
+            .query_row("SELECT MAX(timestamp) FROM logs", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(last.to_rfc3339(), "2025-02-04T16:30:00+00:00");
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!(summary, ImportSummary::default());
+    }
+
+    #[test]
+    fn stats_of_unknown_users_are_retried() {
+        let (mut conn, dir) = setup();
+        write_stats(&dir.path().join("stats/kari.feather"));
+
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!((summary.inserted, summary.malformed), (0, 1));
+
+        fs::write(dir.path().join("users.json"), r#"{"1234567891": "kari"}"#).unwrap();
+        let summary = import_once(&mut conn, dir.path()).unwrap();
+        assert_eq!((summary.inserted, summary.malformed), (2, 0));
+        let quarantined: usize = conn
+            .query_row("SELECT COUNT(*) FROM import_quarantine", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(quarantined, 0);
+    }
+}
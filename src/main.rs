@@ -1,15 +1,46 @@
+mod backup;
+mod bench;
+mod clock;
+mod config;
+mod dashboard;
+mod events;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod generate;
 mod github_map;
+mod import;
+mod metrics;
 mod migrate;
 mod models;
+mod mqtt;
+mod privacy;
+mod pseudonym;
+mod replay;
+mod retention;
+mod server;
+mod snapshot;
+mod sync;
 mod username_popup;
+mod webhooks;
 
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
 use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
-use github_map::{github_map_instructions, GithubMap};
+use backup::backup_now;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeDelta};
+use clap::{Args, Parser, Subcommand};
+use clock::{Clock, OffsetClock, SystemClock};
+use config::{Config, RetentionConfig, SyncConfig};
+use events::events_for;
+use export::{export_ical, export_table, TableFormat};
+use generate::{generate, Behaviour};
+use github_map::{GithubMap, Metric};
+use import::import;
 use itertools::Itertools;
-use migrate::{dump, migrate};
+use migrate::{create_tables, dump, migrate};
 use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
 use ratatui::layout::{Constraint, Layout};
 use ratatui::style::Style;
@@ -24,7 +55,10 @@ use ratatui::{
     DefaultTerminal, Frame,
 };
 
-use models::Person;
+use models::{get_db, get_occupancy, open_db, Person, Stats, DB_PATH, DEFAULT_LOCATION};
+use mqtt::{Command, Mqtt};
+use rusqlite::Connection;
+use snapshot::{restore, snapshot};
 use tui_textarea::TextArea;
 use username_popup::{handle_username_input, render_username_popup};
 
@@ -32,6 +66,15 @@ use username_popup::{handle_username_input, render_username_popup};
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Run the kiosk as if it were this time, e.g. 2025-03-30T04:59:00+01:00. For debugging.
+    /// Backups, sync, webhooks, MQTT, metrics and retention are disabled.
+    #[arg(long, value_name = "TIME", requires = "db")]
+    fake_now: Option<DateTime<FixedOffset>>,
+
+    /// Scratch database to run against with `--fake-now`, so sal.db is left alone
+    #[arg(long, requires = "fake_now")]
+    db: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -39,21 +82,464 @@ enum Commands {
     /// Migrate data from log files into sqlite
     Migrate,
 
+    /// Import new data from the Python prototype, skipping anything already imported
+    Import {
+        /// Directory containing the prototype's `logs/`, `stats/` and `users.json`
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+
+        /// Keep importing new data every given number of seconds
+        #[arg(long, value_name = "SECONDS")]
+        watch: Option<u64>,
+    },
+
     /// Dump data from db into log file format
-    Dump,
+    Dump {
+        /// Overwrite existing log files and users.json
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Write every table of the database to a directory, with checksums
+    Snapshot {
+        dir: PathBuf,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Recreate the database from a snapshot
+    Restore {
+        dir: PathBuf,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+
+        /// Replace the database if it exists
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Back up the database to the configured backup directory
+    Backup {
+        #[command(subcommand)]
+        command: Option<BackupCommand>,
+    },
+
+    /// Serve the dashboard, and users, days and stats as JSON, over HTTP
+    Serve {
+        /// Address to listen on. Defaults to `server.addr` in sal.json
+        #[arg(long)]
+        addr: Option<String>,
+
+        /// Token required to register beeps. Defaults to `server.token` in sal.json
+        #[arg(long)]
+        token: Option<String>,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Collapse old beeps into the first and last of each day
+    Prune {
+        /// Months of beeps to keep as they are. Defaults to `retention.months` in sal.json
+        #[arg(long)]
+        months: Option<u32>,
+
+        /// Only show what would be collapsed
+        #[arg(long)]
+        dry_run: bool,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Upload new beeps and usernames to the central server
+    Sync {
+        /// Base URL of the central server. Defaults to `sync.server` in sal.json
+        #[arg(long)]
+        server: Option<String>,
+
+        /// The central server's token. Defaults to `sync.token` in sal.json
+        #[arg(long)]
+        token: Option<String>,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Play back beeps from a log file, or a directory written by `dump`, through the kiosk
+    Replay {
+        log: PathBuf,
+
+        /// How many times faster than the beeps happened. Pauses are at most two seconds.
+        #[arg(long, default_value_t = 3600.0, value_parser = positive)]
+        speed: f64,
+
+        /// Database to replay into, which must not exist. Defaults to one in memory.
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+
+    /// Fill a new database with made-up users and years of beeps, for benchmarks
+    Generate(GenerateArgs),
+
+    /// Time loading the days of every user, like when they beep
+    Bench {
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+
+        /// Number of times to load every user
+        #[arg(long, default_value_t = 10)]
+        runs: usize,
+    },
+
+    /// Export or erase everything stored about a person
+    Privacy {
+        #[command(subcommand)]
+        command: PrivacyCommand,
+    },
+
+    /// Export statistics for use in other programs
+    Export {
+        #[command(subcommand)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// Check that every backup is a readable database
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum PrivacyCommand {
+    /// Write everything stored about the holder of a card, and their other cards, to JSON
+    Export {
+        #[arg(long)]
+        card: u32,
+
+        /// Defaults to `privacy-<card>.json`
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Delete everything stored about the holder of a card, and their other cards
+    Erase {
+        #[arg(long)]
+        card: u32,
+
+        /// Only show what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Kept in the audit log, e.g. who asked and when
+        #[arg(long)]
+        reason: Option<String>,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportFormat {
+    /// Write a user's days at the reading room to an iCalendar file
+    Ical {
+        /// Username, or card ID for users without a username
+        #[arg(long)]
+        user: String,
+
+        /// Defaults to `<user>.ics`
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        #[arg(long, default_value = DB_PATH)]
+        db: PathBuf,
+    },
+
+    /// Write per-user per-day statistics as CSV
+    Csv(TableArgs),
+
+    /// Write per-user per-day statistics as JSON
+    Json(TableArgs),
+}
+
+#[derive(Args)]
+struct TableArgs {
+    /// First date to include, YYYY-MM-DD
+    #[arg(long)]
+    from: Option<NaiveDate>,
+
+    /// Last date to include, YYYY-MM-DD
+    #[arg(long)]
+    to: Option<NaiveDate>,
+
+    /// Only include this username, or card ID for users without a username
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Only count beeps in this room
+    #[arg(long)]
+    location: Option<String>,
+
+    /// Defaults to stdout
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    #[arg(long, default_value = DB_PATH)]
+    db: PathBuf,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    /// Database to write to, which must not exist
+    db: PathBuf,
+
+    #[arg(long, default_value_t = 200)]
+    users: usize,
+
+    /// Years of beeps up to today
+    #[arg(long, default_value_t = 3)]
+    years: u32,
+
+    /// Usual arrival, HH:MM
+    #[arg(long, default_value = "08:30")]
+    arrival: NaiveTime,
+
+    /// Minutes between the usual arrivals of different users
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(i64).range(0..))]
+    arrival_spread: i64,
+
+    /// Usual hours from arrival to departure
+    #[arg(long, default_value_t = 7.0)]
+    stay: f64,
+
+    /// Hours the stay varies from day to day
+    #[arg(long, default_value_t = 2.0, value_parser = non_negative)]
+    stay_spread: f64,
+
+    /// Chance of coming on a weekday
+    #[arg(long, default_value_t = 0.7, value_parser = probability)]
+    weekday_attendance: f64,
+
+    /// Chance of coming on saturdays and sundays
+    #[arg(long, default_value_t = 0.15, value_parser = probability)]
+    weekend_attendance: f64,
+
+    /// Chance of a beep being registered twice
+    #[arg(long, default_value_t = 0.05, value_parser = probability)]
+    double_beeps: f64,
+
+    /// Chance of leaving without beeping out
+    #[arg(long, default_value_t = 0.03, value_parser = probability)]
+    forgotten_checkouts: f64,
+
+    /// Generate the same data every time
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x > 0.0 => Ok(x),
+        _ => Err(format!("{s} is not a number above 0")),
+    }
+}
+
+fn non_negative(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+        _ => Err(format!("{s} is not a number from 0 and up")),
+    }
+}
+
+fn probability(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("{s} is not a number from 0 to 1")),
+    }
+}
+
+impl GenerateArgs {
+    fn generate(&self) -> io::Result<()> {
+        let hours = |hours: f64| TimeDelta::minutes((hours * 60.0) as i64);
+        let behaviour = Behaviour {
+            arrival: self.arrival,
+            arrival_spread: TimeDelta::minutes(self.arrival_spread),
+            stay: hours(self.stay),
+            stay_spread: hours(self.stay_spread),
+            weekday_attendance: self.weekday_attendance,
+            weekend_attendance: self.weekend_attendance,
+            double_beeps: self.double_beeps,
+            forgotten_checkouts: self.forgotten_checkouts,
+        };
+        generate(&self.db, self.users, self.years, &behaviour, self.seed)
+    }
+}
+
+impl TableArgs {
+    fn export(&self, format: TableFormat) -> io::Result<()> {
+        export_table(
+            &open_db(&self.db),
+            format,
+            self.from,
+            self.to,
+            self.user.as_deref(),
+            self.location.as_deref(),
+            self.output.clone(),
+        )
+    }
 }
 
 fn main() -> io::Result<()> {
     let cli = Cli::parse();
+    let mut config = Config::try_load().map_err(io::Error::other)?;
+    // Before anything touches the database, so every command stores the same IDs
+    if let Some(card_hash) = &config.card_hash {
+        pseudonym::init(card_hash)?;
+    }
     if let Some(command) = &cli.command {
         match command {
             Commands::Migrate => return migrate(),
-            Commands::Dump => return dump(),
+            Commands::Import { root, watch } => {
+                return import(root, watch.map(Duration::from_secs))
+            }
+            Commands::Dump { force } => return dump(*force),
+            Commands::Snapshot { dir, db } => return snapshot(db, dir),
+            Commands::Restore { dir, db, force } => return restore(dir, db, *force),
+            Commands::Backup { command: None } => {
+                // Opening the database would create an empty one to back up
+                if !Path::new(DB_PATH).exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No database at {DB_PATH} to back up"),
+                    ));
+                }
+                let path = backup_now(&get_db(), &config.backup)?;
+                println!("Wrote backup to {}", path.display());
+                return Ok(());
+            }
+            Commands::Backup {
+                command: Some(BackupCommand::Verify),
+            } => return backup::verify(&config.backup),
+            Commands::Serve { addr, token, db } => {
+                if let Some(addr) = addr {
+                    config.server.addr = addr.clone();
+                }
+                if let Some(token) = token {
+                    config.server.token = Some(token.clone());
+                }
+                return server::serve(db, &config);
+            }
+            Commands::Prune {
+                months,
+                dry_run,
+                db,
+            } => {
+                let config =
+                    match (months, config.retention) {
+                        (Some(months), _) => RetentionConfig { months: *months },
+                        (None, Some(config)) => config,
+                        (None, None) => return Err(io::Error::other(
+                            "No retention given, set retention.months in sal.json or use --months",
+                        )),
+                    };
+                return retention::prune(db, &config, *dry_run);
+            }
+            Commands::Sync { server, token, db } => {
+                let mut config = match (server, config.sync) {
+                    (Some(server), config) => SyncConfig {
+                        server: server.trim_end_matches('/').to_string(),
+                        ..config.unwrap_or_else(|| SyncConfig::new(server))
+                    },
+                    (None, Some(config)) => config,
+                    (None, None) => {
+                        return Err(io::Error::other(
+                            "No server given, set sync.server in sal.json or use --server",
+                        ))
+                    }
+                };
+                if let Some(token) = token {
+                    config.token = Some(token.clone());
+                }
+                return sync::sync_now(db, &config);
+            }
+            Commands::Replay { log, speed, db } => {
+                return replay::replay(log, db.as_deref(), *speed)
+            }
+            Commands::Generate(args) => return args.generate(),
+            Commands::Bench { db, runs } => return bench::bench(db, *runs),
+            Commands::Privacy { command } => match command {
+                PrivacyCommand::Export { card, output, db } => {
+                    return privacy::export(db, *card, output.clone())
+                }
+                PrivacyCommand::Erase {
+                    card,
+                    dry_run,
+                    reason,
+                    db,
+                } => return privacy::erase(db, *card, *dry_run, reason.as_deref()),
+            },
+            Commands::Export { format } => match format {
+                ExportFormat::Ical { user, output, db } => {
+                    return export_ical(&open_db(db), user, output.clone())
+                }
+                ExportFormat::Csv(args) => return args.export(TableFormat::Csv),
+                ExportFormat::Json(args) => return args.export(TableFormat::Json),
+            },
+        }
+    }
+
+    let db_path = match &cli.db {
+        Some(path)
+            if Path::new(DB_PATH)
+                .canonicalize()
+                .is_ok_and(|db| path.canonicalize().is_ok_and(|path| path == db)) =>
+        {
+            return Err(io::Error::other(
+                "--fake-now must not run against sal.db, give a scratch database",
+            ))
+        }
+        Some(path) => {
+            // Nothing done at a fake time may leave the scratch database
+            config.backup.enabled = false;
+            config.metrics.addr = None;
+            config.webhooks.clear();
+            config.mqtt = None;
+            config.sync = None;
+            config.retention = None;
+            path.clone()
         }
+        None => PathBuf::from(DB_PATH),
+    };
+    let db = open_db(&db_path);
+
+    create_tables(&db)?;
+    // The background threads open their own connections once the tables exist
+    if let Some(addr) = &config.metrics.addr {
+        metrics::spawn(addr, db_path.clone())?;
+    }
+    if let Some(retention) = &config.retention {
+        retention::prune(&db_path, retention, false)?;
+    }
+    if !config.webhooks.is_empty() {
+        webhooks::spawn_worker(db_path.clone(), config.webhooks.clone());
+    }
+    if let Some(sync) = &config.sync {
+        sync::spawn_worker(db_path.clone(), sync.clone());
     }
 
+    let clock: Box<dyn Clock> = match cli.fake_now {
+        Some(now) => Box::new(OffsetClock::starting_at(now.to_utc())),
+        None => Box::new(SystemClock),
+    };
     let mut terminal = ratatui::init();
-    let app_result = App::new().run(&mut terminal);
+    let app_result = App::new(db, config, clock).run(&mut terminal);
     ratatui::restore();
     println!("Salstatistikk avsluttet eller crashet. For å starte på nytt, klikk pil opp og enter eller skriv `cargo run`");
     app_result
@@ -61,18 +547,33 @@ fn main() -> io::Result<()> {
 
 #[derive(Debug)]
 pub struct App<'a> {
+    /// Shared by everything the app does, so statements are prepared once
+    db: Connection,
     exit: bool,
     buffer: String,
     last_input: Instant,
     current_user: Option<Person>,
     textarea: TextArea<'a>,
     reading_username: bool,
+    config: Config,
+    /// Metric shown in the heatmap, cycled with the arrow keys
+    metric: Metric,
+    last_backup: Option<NaiveDate>,
+    backup_error: Option<String>,
+    /// Why `sal.json` could not be reloaded, while the previous config is kept
+    config_error: Option<String>,
+    /// Why the last beep could not be registered or loaded
+    db_error: Option<String>,
+    mqtt: Option<Mqtt>,
+    /// Commands from the MQTT command topic
+    commands: Option<Receiver<Command>>,
+    clock: Box<dyn Clock>,
 }
 
 const TIMEOUT: Duration = Duration::from_millis(20);
 
 impl<'a> App<'a> {
-    fn new() -> Self {
+    fn new(db: Connection, config: Config, clock: Box<dyn Clock>) -> Self {
         let mut textarea = TextArea::default();
         textarea.set_style(Style::default().white().on_blue());
         textarea.set_block(
@@ -83,13 +584,26 @@ impl<'a> App<'a> {
                 .title_bottom("Avbryt <Esc> Bekreft <Enter>"),
         );
 
+        let metric = config.heatmap.metric;
+        let (mqtt, commands) = config.mqtt.as_ref().map(Mqtt::connect).unzip();
+
         Self {
+            db,
             exit: false,
             buffer: String::with_capacity(12),
             last_input: Instant::now(),
             current_user: None,
             textarea,
             reading_username: false,
+            config,
+            metric,
+            last_backup: None,
+            backup_error: None,
+            config_error: None,
+            db_error: None,
+            mqtt,
+            commands,
+            clock,
         }
     }
 
@@ -102,6 +616,53 @@ impl<'a> App<'a> {
             self.buffer.clear();
             self.last_input = Instant::now();
         }
+        self.backup_if_due();
+        self.handle_commands();
+        self.refresh_at_rollover();
+    }
+
+    /// Counts the stats on screen up to the new date once the day rolls over
+    fn refresh_at_rollover(&mut self) {
+        let today = self.clock.today();
+        let outdated = self
+            .current_user
+            .as_ref()
+            .filter(|user| user.stats.as_of != today)
+            .map(|user| user.id);
+        if let Some(uid) = outdated {
+            self.load_user(uid);
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        let Some(commands) = &self.commands else {
+            return;
+        };
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                Command::Clear => self.current_user = None,
+                Command::Reload => match Config::try_load() {
+                    Ok(config) => {
+                        self.config = config;
+                        self.metric = self.config.heatmap.metric;
+                        self.config_error = None;
+                    }
+                    Err(err) => self.config_error = Some(err),
+                },
+            }
+        }
+    }
+
+    /// Backs up the database on startup and on the first tick of each new day
+    fn backup_if_due(&mut self) {
+        let today = self.clock.today();
+        if !self.config.backup.enabled || self.last_backup == Some(today) {
+            return;
+        }
+        self.last_backup = Some(today);
+        self.backup_error = backup_now(&self.db, &self.config.backup)
+            .err()
+            .map(|err| err.to_string());
     }
 
     /// runs the application's main loop until the user quits
@@ -109,6 +670,7 @@ impl<'a> App<'a> {
         // 5 fps
         let tick_rate = Duration::from_millis(200);
 
+        self.backup_if_due();
         let mut last_tick = Instant::now();
         while !self.exit {
             terminal.draw(|frame| self.draw(frame))?;
@@ -152,8 +714,8 @@ impl<'a> App<'a> {
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
         match key_event.code {
-            KeyCode::Left => self.decrement_counter(),
-            KeyCode::Right => self.increment_counter(),
+            KeyCode::Left => self.metric = self.metric.prev(),
+            KeyCode::Right => self.metric = self.metric.next(),
             KeyCode::Esc => self.exit = true,
             KeyCode::Char(c) if c.is_numeric() => {
                 self.buffer.push(c);
@@ -161,11 +723,8 @@ impl<'a> App<'a> {
             }
             KeyCode::Char(c) => match c.to_ascii_lowercase() {
                 'b' => self.current_user = None,
-                'u' => {
-                    if let Some(_) = self.current_user {
-                        self.reading_username = true
-                    }
-                }
+                'u' if self.current_user.is_some() => self.reading_username = true,
+                'p' => self.toggle_hidden(),
                 _ => (),
             },
             _ => {}
@@ -176,27 +735,109 @@ impl<'a> App<'a> {
         // if self.buffer.len() > 0 {
         //     println!("{}", self.buffer);
         // }
-        if self.buffer.len() == 10 {
-            let Ok(uid): Result<u32, _> = self.buffer.parse() else {
-                return;
-            };
-            self.beep_user(uid);
+        match self.buffer.len() {
+            0 => (),
+            10 => match self.buffer.parse() {
+                Ok(uid) => self.beep_user(uid),
+                Err(_) => metrics::rejected_unparseable(),
+            },
+            _ => metrics::rejected_length(),
         }
     }
 
     fn beep_user(&mut self, uid: u32) {
-        Person::register(uid);
+        match Person::register(&self.db, self.clock.as_ref(), uid, &self.config.location) {
+            Ok(id) => self.load_user(id),
+            Err(err) => {
+                self.current_user = None;
+                self.db_error = Some(err.to_string());
+            }
+        }
+        // Hidden users are not announced anywhere
+        if let Some(user) = self.current_user.as_ref().filter(|user| !user.hidden) {
+            // The beep is registered, so the user stays on screen with the error
+            if let Err(err) = webhooks::enqueue(
+                &self.db,
+                self.clock.as_ref(),
+                &self.config.webhooks,
+                user,
+                &events_for(user),
+            ) {
+                metrics::db_error();
+                self.db_error = Some(format!("webhooks: {err}"));
+            }
+            if let Some(mqtt) = &self.mqtt {
+                let location = Some(self.config.location.as_str());
+                match get_occupancy(&self.db, user.stats.latest.date, location) {
+                    Ok(present) => mqtt.publish_beep(user, present.len()),
+                    Err(err) => {
+                        metrics::db_error();
+                        self.db_error = Some(format!("mqtt: {err}"));
+                    }
+                }
+            }
+        }
+    }
 
-        self.current_user = Some(Person::load(uid));
+    /// Hides the profile of the current user, or shows it again if it is hidden
+    fn toggle_hidden(&mut self) {
+        if let Some(user) = &self.current_user {
+            user.set_hidden(&self.db, self.clock.as_ref(), !user.hidden);
+            self.load_user(user.id);
+        }
     }
 
-    fn increment_counter(&mut self) {}
+    /// Shows the user stored under `uid`, or the error if they could not be loaded
+    fn load_user(&mut self, uid: u64) {
+        match Person::load_in(&self.db, self.clock.as_ref(), uid, None) {
+            Ok(user) => {
+                self.current_user = Some(user);
+                self.db_error = None;
+            }
+            Err(err) => {
+                self.current_user = None;
+                self.db_error = Some(err.to_string());
+            }
+        }
+    }
+}
 
-    fn decrement_counter(&mut self) {}
+fn streak_line(stats: &Stats) -> Line<'static> {
+    let mut line = Line::from("🔥".repeat(stats.streak));
+    if stats.streak_at_risk {
+        line.push_span(" Tæpp i dag for å holde streaken!".red().italic());
+    }
+    line
+}
+
+/// Today's visit, or the last one if the user has not come today
+fn today_line(stats: &Stats) -> Line<'static> {
+    let day = stats.latest.stats();
+    if stats.latest.date == stats.as_of {
+        Line::from(vec![
+            "I dag har du vært her fra ".into(),
+            day.start.yellow(),
+            " som blir ".into(),
+            day.diff.green(),
+        ])
+    } else {
+        Line::from(vec![
+            "Sist du var her var ".into(),
+            day.date.yellow(),
+            " fra ".into(),
+            day.start.yellow(),
+            " som ble ".into(),
+            day.diff.green(),
+        ])
+    }
 }
 
 fn render_welcome_box(frame: &mut Frame, app: &App, area: Rect) {
-    let title = Line::from(" Salstatistikk ".bold());
+    let title = if app.config.location == DEFAULT_LOCATION {
+        Line::from(" Salstatistikk ".bold())
+    } else {
+        Line::from(format!(" Salstatistikk: {} ", app.config.location).bold())
+    };
     let instructions = Line::from(vec![
         " Logg inn ".into(),
         "<Tæpp kortet>".blue().bold(),
@@ -204,18 +845,33 @@ fn render_welcome_box(frame: &mut Frame, app: &App, area: Rect) {
         "<B>".blue().bold(),
         " Endre brukernavn ".into(),
         "<U>".blue().bold(),
+        match &app.current_user {
+            Some(user) if user.hidden => " Vis profil ".into(),
+            _ => " Skjul profil ".into(),
+        },
+        "<P>".blue().bold(),
         " Lukk appen ".into(),
         "<Esc> ".blue().bold(),
     ]);
-    let block = Block::bordered()
+    let mut block = Block::bordered()
         .title(title.centered())
         .title_bottom(instructions.centered())
         .border_set(border::THICK);
+    if let Some(err) = &app.backup_error {
+        let warning = format!(" Sikkerhetskopi feilet: {err} ");
+        block = block.title(Line::from(warning.red()).right_aligned());
+    }
+    if let Some(err) = &app.config_error {
+        let warning = format!(" Kunne ikke laste inn innstillinger: {err} ");
+        block = block.title(Line::from(warning.red()).right_aligned());
+    }
+    if let Some(err) = &app.db_error {
+        let warning = format!(" Kunne ikke registrere tæpp: {err} ");
+        block = block.title(Line::from(warning.red()).left_aligned());
+    }
 
     let text = match &app.current_user {
         None => {
-            let _ = 1;
-
             Text::from(vec![
                 Line::from("Instruksjoner: ".blue()),
                 Line::from(""),
@@ -227,9 +883,14 @@ fn render_welcome_box(frame: &mut Frame, app: &App, area: Rect) {
                 Line::from("*Dagen varer fra 05:00 til 04:59.".italic()),
             ].into_iter().map(|line| line.left_aligned()).collect_vec())
         }
+        // Others may be looking at the screen
+        Some(user) if user.hidden => Text::from(vec![
+            Line::from("Tæpp registrert".green()),
+            Line::from(""),
+            Line::from("Profilen din er skjult. Trykk <P> for å vise den igjen.".italic()),
+        ]),
         Some(user) => {
             let longest = user.stats.longest_day.stats();
-            let today = user.stats.today.stats();
             let earliest_arrival = user.stats.earliest_arrival.stats();
             let latest_departure = user.stats.latest_departure.stats();
             Text::from(
@@ -238,13 +899,8 @@ fn render_welcome_box(frame: &mut Frame, app: &App, area: Rect) {
                         "Velkommen ".into(),
                         user.username.to_string().yellow(),
                     ]),
-                    Line::from(vec!["🔥".repeat(user.stats.streak).into()]),
-                    Line::from(vec![
-                        "I dag har du vært her fra ".into(),
-                        today.start.yellow(),
-                        " som blir ".into(),
-                        today.diff.green(),
-                    ]),
+                    streak_line(&user.stats),
+                    today_line(&user.stats),
                     Line::from(vec![
                         "Lengste dag: ".into(),
                         longest.date.yellow(),
@@ -290,9 +946,15 @@ fn render_welcome_box(frame: &mut Frame, app: &App, area: Rect) {
 }
 
 fn render_github_stats(frame: &mut Frame, app: &App, area: Rect) {
-    if let Some(user) = &app.current_user {
-        let title = Line::centered(" Oppmøtehistorikk ".into()).blue();
-        let instrs = Line::from(github_map_instructions()).centered();
+    if let Some(user) = app.current_user.as_ref().filter(|user| !user.hidden) {
+        let title = Line::from(vec![
+            " Oppmøtehistorikk: ".into(),
+            app.metric.title().into(),
+            " <←/→> ".bold(),
+        ])
+        .blue();
+        let scale = app.config.heatmap.scale(app.metric);
+        let instrs = Line::from(scale.legend()).centered();
         let block = Block::bordered()
             .title(title.centered())
             .title_bottom(instrs.bold())
@@ -301,7 +963,196 @@ fn render_github_stats(frame: &mut Frame, app: &App, area: Rect) {
 
         frame.render_widget(&block, area);
         let inner = block.inner(area);
-        let gh_map = GithubMap::new(&user.stats.days_milliseconds);
+        let today = app.clock.today();
+        let gh_map = GithubMap::new(&user.stats.days, &scale, today);
         frame.render_widget(gh_map, inner);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use clap::Parser;
+    use ratatui::backend::TestBackend;
+    use ratatui::buffer::Buffer;
+    use ratatui::crossterm::event::KeyCode;
+    use ratatui::style::Color;
+    use ratatui::Terminal;
+
+    use super::{App, Cli};
+    use crate::clock::FixedClock;
+    use crate::config::{Config, WebhookConfig};
+    use crate::fixtures::{oslo, Fixture};
+    use crate::github_map::Metric;
+
+    /// An app showing `fixture` at 09:00 on tuesday 11 February 2025
+    fn app(fixture: Fixture) -> App<'static> {
+        let clock = FixedClock::new(oslo("2025-02-11 09:00"));
+        App::new(fixture.build(), Config::default(), Box::new(clock))
+    }
+
+    /// Types the card number like the card reader does
+    fn beep(app: &mut App, card: u32) {
+        for c in card.to_string().chars() {
+            app.handle_key_event(KeyCode::Char(c).into());
+        }
+        app.process_buffer();
+        app.buffer.clear();
+    }
+
+    fn render(app: &App) -> Buffer {
+        let mut terminal = Terminal::new(TestBackend::new(100, 42)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        terminal.backend().buffer().clone()
+    }
+
+    /// The text of the first `n` lines, without styles and the left and right borders.
+    /// Wide characters are followed by a blank cell.
+    fn text(buf: &Buffer, n: u16) -> Vec<String> {
+        (0..n)
+            .map(|y| {
+                let line: String = (0..buf.area.width).map(|x| buf[(x, y)].symbol()).collect();
+                line.trim_matches(|c| c == '┃' || c == ' ').to_string()
+            })
+            .collect()
+    }
+
+    /// Background of the heatmap square `weeks_back` columns left of today's.
+    /// The map is drawn inside the border and padding of the lower block.
+    fn square(buf: &Buffer, weeks_back: u16, weekday: u16) -> Color {
+        buf[(93 - 6 * (weeks_back + 1) + 2, 14 + 3 * weekday + 1)].bg
+    }
+
+    /// Ola was here yesterday, and once long ago
+    fn ola() -> Fixture {
+        Fixture::new()
+            .beeps(
+                1234567890,
+                &[
+                    "2024-06-03 10:00",
+                    "2024-06-03 12:00",
+                    "2025-02-10 08:00",
+                    "2025-02-10 16:30",
+                ],
+            )
+            .user("ola", &[1234567890])
+    }
+
+    #[test]
+    fn beeping_shows_stats_and_heatmap() {
+        let mut app = app(ola());
+        assert_eq!(text(&render(&app), 3)[2], "Instruksjoner:");
+
+        beep(&mut app, 1234567890);
+        let buf = render(&app);
+        assert_eq!(
+            text(&buf, 12),
+            [
+                "┏━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━ Salstatistikk ━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┓",
+                "",
+                "Velkommen ola",
+                "🔥 🔥",
+                "I dag har du vært her fra 09:00 som blir 0 minutter og 0 sekunder",
+                "Lengste dag: 10/02. Fra 08:00 til: 16:30. Det er hele 8 timer og 30 minutter",
+                "Tidligste ankomst: 08:00 den 10/02",
+                "Seneste avreise: 16:30 den 10/02",
+                "Antall møtte siste syv dager: 2",
+                "Antall møtte siste 30 dager: 2",
+                "",
+                "┗━━━ Logg inn <Tæpp kortet> Logg ut <B> Endre brukernavn <U> Skjul profil <P> Lukk appen <Esc> ━━━━┛",
+            ]
+        );
+
+        let scale = app.config.heatmap.scale(Metric::Duration);
+        assert_eq!(square(&buf, 0, 1), scale.color(Some(0)));
+        assert_eq!(square(&buf, 0, 0), scale.color(Some(8 * 60 + 30)));
+        // Wednesday has not happened yet, and the friday before was a day off
+        assert_eq!(square(&buf, 0, 2), Color::Reset);
+        assert_eq!(square(&buf, 1, 4), scale.color(None));
+
+        app.handle_key_event(KeyCode::Right.into());
+        assert!(text(&render(&app), 13)[12].contains("Ankomsttid"));
+        app.handle_key_event(KeyCode::Char('b').into());
+        assert_eq!(text(&render(&app), 3)[2], "Instruksjoner:");
+    }
+
+    #[test]
+    fn hidden_users_only_get_a_confirmation() {
+        let clock = Rc::new(FixedClock::new(oslo("2025-02-11 09:00")));
+        let mut app = App::new(ola().build(), Config::default(), Box::new(clock.clone()));
+        beep(&mut app, 1234567890);
+        app.handle_key_event(KeyCode::Char('p').into());
+        let buf = render(&app);
+        assert_eq!(
+            text(&buf, 5)[2..5],
+            [
+                "Tæpp registrert",
+                "",
+                "Profilen din er skjult. Trykk <P> for å vise den igjen.",
+            ]
+        );
+        assert!(text(&buf, 12)[11].contains("Vis profil <P>"));
+        // No heatmap
+        assert_eq!(square(&buf, 0, 1), Color::Reset);
+
+        // Still hidden after logging out and in again, until shown again
+        app.handle_key_event(KeyCode::Char('b').into());
+        app.load_user(1234567890);
+        assert_eq!(text(&render(&app), 3)[2], "Tæpp registrert");
+        // Changes are stamped with the app's clock, and only newer ones win
+        clock.set(oslo("2025-02-11 09:01"));
+        app.handle_key_event(KeyCode::Char('p').into());
+        assert_eq!(text(&render(&app), 3)[2], "Velkommen ola");
+    }
+
+    #[test]
+    fn streaks_at_risk_are_shown_after_rollover() {
+        let clock = Rc::new(FixedClock::new(oslo("2025-02-11 09:00")));
+        let mut app = App::new(ola().build(), Config::default(), Box::new(clock.clone()));
+        beep(&mut app, 1234567890);
+        clock.set(oslo("2025-02-12 04:59"));
+        app.refresh_at_rollover();
+        assert_eq!(text(&render(&app), 4)[3], "🔥 🔥");
+
+        clock.set(oslo("2025-02-12 05:00"));
+        app.refresh_at_rollover();
+        let lines = text(&render(&app), 9);
+        assert_eq!(lines[3], "🔥 🔥  Tæpp i dag for å holde streaken!");
+        assert_eq!(
+            lines[4],
+            "Sist du var her var 11/02 fra 09:00 som ble 0 minutter og 0 sekunder"
+        );
+        assert_eq!(lines[8], "Antall møtte siste syv dager: 2");
+    }
+
+    #[test]
+    fn failed_webhook_queueing_keeps_the_user_on_screen() {
+        let conn = ola().build();
+        conn.execute("DROP TABLE webhook_queue", ()).unwrap();
+        let config = Config {
+            webhooks: vec![WebhookConfig {
+                url: "http://localhost".to_string(),
+                events: vec![],
+                secret: None,
+                template: None,
+            }],
+            ..Config::default()
+        };
+        let clock = FixedClock::new(oslo("2025-02-11 09:00"));
+        let mut app = App::new(conn, config, Box::new(clock));
+        beep(&mut app, 1234567890);
+        assert_eq!(app.current_user.as_ref().unwrap().username, "ola");
+        assert!(app.db_error.unwrap().starts_with("webhooks: "));
+    }
+
+    #[test]
+    fn fake_time_needs_a_scratch_database() {
+        let now = "--fake-now=2025-03-30T04:59:00+01:00";
+        assert!(Cli::try_parse_from(["sal", now]).is_err());
+        assert!(Cli::try_parse_from(["sal", "--db=scratch.db"]).is_err());
+        let cli = Cli::try_parse_from(["sal", now, "--db=scratch.db"]).unwrap();
+        assert_eq!(cli.db.unwrap(), PathBuf::from("scratch.db"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use tiny_http::{Header, Response, Server};
+
+use crate::models::{get_locations, get_occupancy, open_db, rollover_date};
+
+static BEEPS: AtomicU64 = AtomicU64::new(0);
+static REJECTED_LENGTH: AtomicU64 = AtomicU64::new(0);
+static REJECTED_UNPARSEABLE: AtomicU64 = AtomicU64::new(0);
+static DB_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Upper bounds in seconds of the `Person::load` latency buckets
+const LOAD_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+/// Loads in each bucket, with one extra for slower loads
+static LOAD_COUNTS: [AtomicU64; LOAD_BUCKETS.len() + 1] =
+    [const { AtomicU64::new(0) }; LOAD_BUCKETS.len() + 1];
+static LOAD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+pub fn beep() {
+    BEEPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Input from the card reader that was not 10 digits
+pub fn rejected_length() {
+    REJECTED_LENGTH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 10 digits that do not fit a card ID
+pub fn rejected_unparseable() {
+    REJECTED_UNPARSEABLE.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn db_error() {
+    DB_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn observe_load(elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let bucket = LOAD_BUCKETS.iter().take_while(|le| seconds > **le).count();
+    LOAD_COUNTS[bucket].fetch_add(1, Ordering::Relaxed);
+    LOAD_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Serves `/metrics` from a background thread with its own connection
+pub fn spawn(addr: &str, db: PathBuf) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    thread::spawn(move || {
+        let conn = open_db(&db);
+        for request in server.incoming_requests() {
+            let response = match request.url() {
+                "/metrics" => match render(&conn) {
+                    Ok(text) => Response::from_string(text)
+                        .with_header(Header::from_bytes("Content-Type", CONTENT_TYPE).unwrap()),
+                    Err(err) => {
+                        db_error();
+                        Response::from_string(err.to_string()).with_status_code(500)
+                    }
+                },
+                _ => Response::from_string("Not found").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                eprintln!("Failed to respond: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Escapes a label value, which may not hold raw backslashes, quotes or newlines
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Every metric in the Prometheus text format.
+/// Occupancy and the last beep are read from the database, so they are correct across processes.
+pub fn render(conn: &Connection) -> rusqlite::Result<String> {
+    let today = rollover_date(Utc::now());
+    let occupancy = get_locations(conn)?
+        .into_iter()
+        .map(|(location, _)| {
+            let count = get_occupancy(conn, today, Some(&location))?.len();
+            let location = escape_label(&location);
+            Ok((format!("{{location=\"{location}\"}}"), count.to_string()))
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let last_beep: Option<DateTime<Utc>> =
+        conn.query_row("SELECT MAX(timestamp) FROM logs", [], |row| row.get(0))?;
+
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, String)]| {
+        writeln!(text, "# HELP {name} {help}").unwrap();
+        writeln!(text, "# TYPE {name} {kind}").unwrap();
+        for (labels, value) in values {
+            writeln!(text, "{name}{labels} {value}").unwrap();
+        }
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+
+    metric(
+        "sal_beeps_total",
+        "counter",
+        "Beeps registered by this process",
+        &[("", load(&BEEPS))],
+    );
+    metric(
+        "sal_rejected_buffers_total",
+        "counter",
+        "Card reader input that was not a card ID",
+        &[
+            ("{reason=\"length\"}", load(&REJECTED_LENGTH)),
+            ("{reason=\"unparseable\"}", load(&REJECTED_UNPARSEABLE)),
+        ],
+    );
+    metric(
+        "sal_db_errors_total",
+        "counter",
+        "Failed database queries when registering beeps, loading users and delivering webhooks",
+        &[("", load(&DB_ERRORS))],
+    );
+    metric(
+        "sal_occupancy",
+        "gauge",
+        "Users in each room",
+        &occupancy
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), value.clone()))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "sal_last_beep_timestamp_seconds",
+        "gauge",
+        "Time of the last beep registered by any process",
+        &[("", last_beep.map_or(0, |time| time.timestamp()).to_string())],
+    );
+
+    let mut buckets = vec![];
+    let mut cumulative = 0;
+    for (i, counter) in LOAD_COUNTS.iter().enumerate() {
+        cumulative += counter.load(Ordering::Relaxed);
+        let le = LOAD_BUCKETS
+            .get(i)
+            .map_or("+Inf".to_string(), f64::to_string);
+        buckets.push((format!("_bucket{{le=\"{le}\"}}"), cumulative.to_string()));
+    }
+    let sum = LOAD_MICROS.load(Ordering::Relaxed) as f64 / 1e6;
+    buckets.push(("_sum".to_string(), sum.to_string()));
+    buckets.push(("_count".to_string(), cumulative.to_string()));
+    let buckets: Vec<_> = buckets
+        .iter()
+        .map(|(suffix, value)| (suffix.as_str(), value.clone()))
+        .collect();
+    metric(
+        "sal_person_load_seconds",
+        "histogram",
+        "Time spent loading a user and their stats",
+        &buckets,
+    );
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use super::{escape_label, observe_load, render};
+    use crate::fixtures::{today, Fixture};
+
+    #[test]
+    fn metrics_are_rendered() {
+        let conn = Fixture::new().beeps(1234567890, &[&today("08:00")]).build();
+        observe_load(Duration::from_millis(3));
+
+        let text = render(&conn).unwrap();
+        assert!(text.contains("# TYPE sal_beeps_total counter\n"));
+        assert!(text.contains("sal_occupancy{location=\"sal\"} 1\n"));
+        assert!(text.contains("sal_rejected_buffers_total{reason=\"length\"} "));
+        // The counters are shared with tests running in parallel, so exact values are unknown
+        assert!(text.contains("sal_person_load_seconds_bucket{le=\"0.001\"} "));
+        assert!(!text.contains("sal_person_load_seconds_count 0\n"));
+        assert!(!text.contains("sal_last_beep_timestamp_seconds 0\n"));
+    }
+
+    #[test]
+    fn database_errors_are_returned() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(render(&conn).is_err());
+    }
+
+    #[test]
+    fn rooms_are_escaped_in_labels() {
+        assert_eq!(escape_label("sal"), "sal");
+        assert_eq!(
+            escape_label("\"kjeller\"\\\nloft"),
+            r#"\"kjeller\"\\\nloft"#
+        );
+    }
+}
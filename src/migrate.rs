@@ -1,16 +1,60 @@
 use chrono::DateTime;
-use chrono::TimeDelta;
+use chrono::NaiveDate;
 use chrono::Utc;
-use chrono_tz::Europe::Oslo;
+use itertools::Itertools;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::path::Path;
 
-use crate::models::get_db;
+use rusqlite::Connection;
 
-pub fn migrate() -> io::Result<()> {
-    let conn = get_db();
+use crate::models::{get_db, rollover_date, DEFAULT_LOCATION};
+use crate::pseudonym::{self, card_id, HASHED_MARKER};
+
+/// Changes to the tables made after they were first created. Entry `i` upgrades the schema
+/// from `PRAGMA user_version` `i` to `i + 1`.
+fn migrations() -> [String; 5] {
+    [
+        // The room a beep was registered in. Beeps from before rooms existed were all in one room.
+        format!(
+            "ALTER TABLE logs ADD COLUMN location TEXT NOT NULL DEFAULT '{DEFAULT_LOCATION}';
+            CREATE INDEX logs_location_idx ON logs (location);"
+        ),
+        // When the username was last set, so the newest wins when kiosks sync the same card
+        "ALTER TABLE people ADD COLUMN changed TEXT;".to_string(),
+        // Number of beeps a row stands for. Above 1 for the last beep of days collapsed by `prune`.
+        "ALTER TABLE logs ADD COLUMN beeps INTEGER NOT NULL DEFAULT 1;".to_string(),
+        // Card ID found in a quarantined line, so the line can be found by `privacy`
+        "ALTER TABLE import_quarantine ADD COLUMN id INTEGER;".to_string(),
+        // Card ID of the user a delivery is about, so `privacy` finds it without reading the body
+        "ALTER TABLE webhook_queue ADD COLUMN user_id INTEGER;".to_string(),
+    ]
+}
+
+/// Creates every table and index used by sal, if they do not already exist,
+/// and applies any migrations the database has not seen yet
+pub fn create_tables(conn: &Connection) -> io::Result<()> {
+    create_initial_tables(conn);
 
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(io::Error::other)?;
+    for (i, migration) in migrations().iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN;
+            {migration}
+            PRAGMA user_version = {};
+            COMMIT;",
+            i + 1
+        ))
+        .map_err(io::Error::other)?;
+    }
+
+    pseudonym::check(conn)
+}
+
+fn create_initial_tables(conn: &Connection) {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS logs (
             id         INTEGER,
@@ -43,19 +87,178 @@ pub fn migrate() -> io::Result<()> {
     )
     .unwrap();
 
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS people (
+            id    INTEGER PRIMARY KEY,
+            username  TEXT NOT NULL
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS people_id_idx
+            ON people(id)",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS coffee (
+            id    INTEGER PRIMARY KEY,
+            timestamp  TEXT NOT NULL,
+            date       TEXT NOT NULL
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS coffee_id_idx
+            ON coffee(id)",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS coffee_timestamp_idx
+            ON coffee (timestamp)",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS coffee_date_idx
+            ON coffee (date)",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // Progress of `import`, so files from the Python prototype are only read once
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS imported_files (
+            path      TEXT PRIMARY KEY,
+            offset    INTEGER NOT NULL,
+            lines     INTEGER NOT NULL,
+            modified  TEXT
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS imported_users (
+            id        INTEGER PRIMARY KEY,
+            username  TEXT NOT NULL
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS import_quarantine (
+            path         TEXT NOT NULL,
+            line_number  INTEGER NOT NULL,
+            line         TEXT NOT NULL,
+            error        TEXT NOT NULL,
+            PRIMARY KEY (path, line_number)
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // Webhook deliveries not yet sent, including failed ones waiting for a retry
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_queue (
+            id            INTEGER PRIMARY KEY,
+            url           TEXT NOT NULL,
+            body          TEXT NOT NULL,
+            attempts      INTEGER NOT NULL DEFAULT 0,
+            next_attempt  TEXT NOT NULL,
+            last_error    TEXT
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // Exports and erasures of personal data. Only the number of rows is kept, not whose they were.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS privacy_audit (
+            id         INTEGER PRIMARY KEY,
+            timestamp  TEXT NOT NULL,
+            action     TEXT NOT NULL,
+            rows       TEXT NOT NULL,
+            reason     TEXT
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // Identifies the key card IDs are hashed with. Empty when they are stored in clear.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS card_hash (
+            fingerprint  TEXT NOT NULL
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // Profiles hidden from dashboards, rankings and exports. Rows are kept when shown again,
+    // so the latest change wins when kiosks sync.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS visibility (
+            id       INTEGER PRIMARY KEY,
+            hidden   INTEGER NOT NULL,
+            changed  TEXT NOT NULL
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+
+    // How far this kiosk has uploaded to each central server
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_state (
+            server         TEXT PRIMARY KEY,
+            logs_rowid     INTEGER NOT NULL DEFAULT 0,
+            users_changed  TEXT,
+            last_attempt   TEXT,
+            last_error     TEXT
+        )",
+        (), // empty list of parameters.
+    )
+    .unwrap();
+}
+
+pub fn migrate() -> io::Result<()> {
+    let conn = get_db();
+    create_tables(&conn)?;
+    // Files written by `dump` from a database with hashed card IDs must not be hashed again
+    let card_id: fn(u64) -> u64 = match Path::new(HASHED_MARKER).exists() {
+        true => |id| id,
+        false => card_id,
+    };
+
     for file in fs::read_dir("logs").expect("logs dir to exist") {
         let file = file.unwrap();
         let path = file.path();
         if path.is_file() {
-            let mut rdr = csv::Reader::from_path(path).unwrap();
+            let mut rdr = csv::ReaderBuilder::new()
+                .flexible(true)
+                .from_path(path)
+                .unwrap();
             for line in rdr.records() {
                 let parts = line.unwrap();
                 let timestamp = DateTime::parse_from_rfc3339(&parts[0]).unwrap();
-                let date = (timestamp.with_timezone(&Oslo) - TimeDelta::hours(5)).date_naive();
-                let userid: u64 = parts[1].parse().unwrap();
+                let date = rollover_date(timestamp.to_utc());
+                let userid = card_id(parts[1].parse().unwrap());
+                // Written by `dump` for beeps outside the default room
+                let location = parts.get(2).unwrap_or(DEFAULT_LOCATION);
+                // Written by `dump` for the last beep of days collapsed by `prune`
+                let beeps: u32 = parts.get(3).map_or(1, |beeps| beeps.parse().unwrap());
                 let res = conn.execute(
-                    "INSERT INTO logs (id, timestamp, date) VALUES (?1, ?2, ?3)",
-                    (&userid, &timestamp, &date),
+                    "INSERT INTO logs (id, timestamp, date, location, beeps)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    (&userid, &timestamp, &date, location, beeps),
                 );
                 match res {
                     Ok(_) => (),
@@ -65,27 +268,11 @@ pub fn migrate() -> io::Result<()> {
         }
     }
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS people (
-            id    INTEGER PRIMARY KEY,
-            username  TEXT NOT NULL
-        )",
-        (), // empty list of parameters.
-    )
-    .unwrap();
-
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS people_id_idx
-            ON people(id)",
-        (), // empty list of parameters.
-    )
-    .unwrap();
-
     let json_file = fs::read_to_string("users.json").unwrap();
     let users = json::parse(&json_file).unwrap();
 
     for (userid, username) in users.entries() {
-        let userid: u64 = userid.parse().unwrap();
+        let userid = card_id(userid.parse().unwrap());
         let username = username.as_str().unwrap();
 
         let res = conn.execute(
@@ -98,16 +285,15 @@ pub fn migrate() -> io::Result<()> {
         }
     }
 
-    add_coffee_table()?;
-
     Ok(())
 }
 
-pub fn dump() -> io::Result<()> {
+/// Writes logs to one file per rollover date, refusing to overwrite existing files unless `force`
+pub fn dump(force: bool) -> io::Result<()> {
     let conn = get_db();
 
     let mut logs_stmt = conn
-        .prepare("SELECT id, timestamp FROM logs ORDER BY timestamp ASC")
+        .prepare("SELECT id, timestamp, date, location, beeps FROM logs ORDER BY timestamp ASC")
         .unwrap();
 
     let mut days = HashMap::new();
@@ -115,24 +301,52 @@ pub fn dump() -> io::Result<()> {
         .query_map([], |row| {
             let id: u64 = row.get(0).unwrap();
             let timestamp: DateTime<Utc> = row.get(1).unwrap();
-            Ok((id, timestamp))
+            let date: NaiveDate = row.get(2).unwrap();
+            let location: String = row.get(3).unwrap();
+            let beeps: u32 = row.get(4).unwrap();
+            Ok((id, timestamp, date, location, beeps))
         })
         .unwrap();
     for row in logs_res {
-        let (id, timestamp) = row.unwrap();
-        let date = timestamp.format("%Y%m%d").to_string();
+        let (id, timestamp, date, location, beeps) = row.unwrap();
+        let date = date.format("%Y%m%d").to_string();
         days.entry(date)
             .or_insert_with(Vec::new)
-            .push((timestamp, id));
+            .push((timestamp, id, location, beeps));
+    }
+
+    let existing = ["users.json".to_string()]
+        .into_iter()
+        .chain(days.keys().map(|date| format!("logs/{date}.log")))
+        .filter(|path| Path::new(path).exists())
+        .collect_vec();
+    if !force && !existing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "Refusing to overwrite {}. Use --force to overwrite",
+                existing.join(", ")
+            ),
+        ));
     }
 
     fs::create_dir_all("logs").unwrap();
     for (date, entries) in days {
-        let mut writer = csv::Writer::from_path(format!("logs/{date}.log")).unwrap();
-        for (timestamp, id) in entries {
-            writer
-                .write_record(&[timestamp.to_rfc3339(), id.to_string()])
-                .unwrap();
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_path(format!("logs/{date}.log"))
+            .unwrap();
+        for (timestamp, id, location, beeps) in entries {
+            let mut record = vec![timestamp.to_rfc3339(), id.to_string()];
+            // Keeps the files readable by the Python prototype when there is only one room
+            // and nothing was collapsed
+            if location != DEFAULT_LOCATION || beeps > 1 {
+                record.push(location);
+            }
+            if beeps > 1 {
+                record.push(beeps.to_string());
+            }
+            writer.write_record(&record).unwrap();
         }
     }
 
@@ -150,43 +364,46 @@ pub fn dump() -> io::Result<()> {
 
     let dumped = json::stringify_pretty(user_map, 2);
     fs::write("users.json", dumped).unwrap();
+    if pseudonym::is_hashed(&conn) {
+        fs::write(HASHED_MARKER, "").unwrap();
+    } else if Path::new(HASHED_MARKER).exists() {
+        fs::remove_file(HASHED_MARKER).unwrap();
+    }
 
     Ok(())
 }
 
-pub fn add_coffee_table() -> io::Result<()> {
-    let conn = get_db();
+#[cfg(test)]
+mod tests {
+    use super::{create_tables, migrations};
+    use crate::models::{open_db, DEFAULT_LOCATION};
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS coffee (
-            id    INTEGER PRIMARY KEY,
-            timestamp  TEXT NOT NULL,
-            date       TEXT NOT NULL
-        )",
-        (), // empty list of parameters.
-    )
-    .unwrap();
-
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS coffee_id_idx
-            ON coffee(id)",
-        (), // empty list of parameters.
-    )
-    .unwrap();
-
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS coffee_timestamp_idx
-            ON coffee (timestamp)",
-        (), // empty list of parameters.
-    )
-    .unwrap();
+    #[test]
+    fn old_databases_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_db(&dir.path().join("sal.db"));
+        conn.execute_batch(
+            "CREATE TABLE logs (
+                id         INTEGER,
+                timestamp  TEXT NOT NULL,
+                date       TEXT NOT NULL,
+                PRIMARY KEY (id, timestamp)
+            );
+            INSERT INTO logs VALUES (1234567890, '2025-02-04T08:00:00Z', '2025-02-04');",
+        )
+        .unwrap();
 
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS coffee_date_idx
-            ON coffee (date)",
-        (), // empty list of parameters.
-    )
-    .unwrap();
+        create_tables(&conn).unwrap();
+        // Running again must not apply the migrations twice
+        create_tables(&conn).unwrap();
 
-    Ok(())
+        let location: String = conn
+            .query_row("SELECT location FROM logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(location, DEFAULT_LOCATION);
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, migrations().len());
+    }
 }
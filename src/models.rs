@@ -1,16 +1,58 @@
+use std::iter::Peekable;
+use std::path::Path;
 use std::rc::Rc;
+use std::slice;
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Utc, Weekday};
 use chrono_tz::{Europe::Oslo, Tz};
 use itertools::Itertools;
-use rusqlite::{types::Value, vtab::array, Connection};
+use rusqlite::{types::Value, vtab::array, Connection, OptionalExtension};
+
+use crate::clock::Clock;
+use crate::metrics;
+use crate::pseudonym::card_id;
+
+pub const DB_PATH: &str = "sal.db";
+
+/// Room of beeps from before sal knew about rooms, and of readers with no location configured
+pub const DEFAULT_LOCATION: &str = "sal";
+
+/// The ID beeps of `card` are stored under
+fn stored_id(card: u32) -> u64 {
+    card_id(card.into())
+}
+
+/// Stored IDs as a parameter for `rarray`
+pub fn id_array(ids: &[u64]) -> Rc<Vec<Value>> {
+    Rc::new(ids.iter().map(|id| Value::Integer(*id as i64)).collect())
+}
 
 pub fn get_db() -> Connection {
-    let db = Connection::open("sal.db").unwrap();
+    open_db(Path::new(DB_PATH))
+}
+
+/// How long to wait for another connection's write lock before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a connection meant to be kept for the lifetime of the program.
+/// WAL lets other processes, like exports and the web view, read while beeps are written.
+/// `:memory:` opens a new empty database only this connection sees.
+pub fn open_db(path: &Path) -> Connection {
+    let db = Connection::open(path).unwrap();
+    db.pragma_update(None, "journal_mode", "WAL").unwrap();
+    db.busy_timeout(BUSY_TIMEOUT).unwrap();
+    db.set_prepared_statement_cache_capacity(32);
     array::load_module(&db).unwrap();
     db
 }
 
+/// The date a beep counts towards. Days last from 05:00 to 04:59 Oslo time.
+/// The wall clock is used, so days still start at 05:00 on the nights clocks change.
+pub fn rollover_date(timestamp: DateTime<Utc>) -> NaiveDate {
+    (timestamp.with_timezone(&Oslo).naive_local() - TimeDelta::hours(5)).date()
+}
+
 /// Tablename `logs`
 #[allow(unused)]
 #[derive(Debug)]
@@ -18,44 +60,57 @@ pub struct Log {
     timestamp: DateTime<Utc>,
     /// Date of beep-time minus five hours in Oslo time
     date: NaiveDate,
-    id: u32,
+    id: u64,
 }
 
 /// Tablename `people`
 #[allow(unused)]
 #[derive(Debug)]
 pub struct Person {
-    pub id: u32,
-    pub ids: Vec<u32>,
+    pub id: u64,
+    pub ids: Vec<u64>,
     pub username: String,
+    /// Whether the user has hidden their profile from dashboards, rankings and exports
+    pub hidden: bool,
     pub stats: Stats,
 }
 
 impl Person {
-    pub fn load(uid: u32) -> Self {
-        let conn = get_db();
-        let username_res =
-            conn.query_row("SELECT username FROM people WHERE id=($1)", (uid,), |row| {
-                let un = row.get(0).unwrap();
-                Ok(un)
-            });
-        // .unwrap_or_else(|uid| uid.to_string());
-        let (username, ids) = if let Ok(username) = username_res {
-            let mut ids_stmt = conn
-                .prepare("SELECT id FROM people WHERE USERNAME=($1)")
-                .unwrap();
-
-            let ids = ids_stmt
-                .query_map([&username], |row| {
-                    let id: u32 = row.get(0).unwrap();
-
-                    Ok(id)
-                })
-                .unwrap();
-            let ids: Result<Vec<_>, _> = ids.collect();
-            let ids = ids.unwrap();
+    /// Loads the holder of `card` with stats from every room. A day spanning several rooms
+    /// counts once, from the first beep in any room to the last.
+    pub fn load(conn: &Connection, clock: &dyn Clock, card: u32) -> rusqlite::Result<Self> {
+        Self::load_in(conn, clock, stored_id(card), None)
+    }
+
+    /// Loads the user stored under `uid` with stats from one room, or every room if `location`
+    /// is `None`
+    pub fn load_in(
+        conn: &Connection,
+        clock: &dyn Clock,
+        uid: u64,
+        location: Option<&str>,
+    ) -> rusqlite::Result<Self> {
+        let started = Instant::now();
+        let person = Self::load_inner(conn, clock.today(), uid, location)
+            .inspect_err(|_| metrics::db_error());
+        metrics::observe_load(started.elapsed());
+        person
+    }
+
+    fn load_inner(
+        conn: &Connection,
+        today: NaiveDate,
+        uid: u64,
+        location: Option<&str>,
+    ) -> rusqlite::Result<Self> {
+        let username: Option<String> = conn
+            .prepare_cached("SELECT username FROM people WHERE id=($1)")?
+            .query_row((uid,), |row| row.get(0))
+            .optional()?;
+        let (username, ids) = if let Some(username) = username {
+            let ids = get_ids(conn, &username)?;
             assert!(
-                ids.len() != 0,
+                !ids.is_empty(),
                 "Failed to load IDs of user. Should never happen."
             );
             (username, ids)
@@ -63,170 +118,412 @@ impl Person {
             (uid.to_string(), vec![uid])
         };
 
-        let stats = Stats::load_for_user(&ids);
+        let stats = Stats::load_for_user(conn, today, &ids, location)?;
+        let hidden = is_hidden(conn, &ids)?;
 
-        Self {
+        Ok(Self {
             id: uid,
             ids,
             username,
+            hidden,
             stats,
-        }
+        })
     }
 
-    pub fn register(uid: u32) {
-        let conn = get_db();
-        let now = Utc::now();
-        let date = (now.with_timezone(&Oslo) - TimeDelta::hours(5)).date_naive();
-        conn.execute(
-            "INSERT INTO logs (id, timestamp, date) VALUES (?1, ?2, ?3)",
-            (&uid, &now, &date),
+    /// Loads the user with `username`, or the card ID if no username is set.
+    /// Users that never beeped, in `location` if given, and hidden users are not found.
+    pub fn find(
+        conn: &Connection,
+        clock: &dyn Clock,
+        username: &str,
+        location: Option<&str>,
+    ) -> rusqlite::Result<Option<Self>> {
+        let ids = ids_for_username(conn, username)?;
+        let has_logs: bool = conn
+            .prepare_cached(
+                "SELECT EXISTS (
+                    SELECT 1 FROM logs WHERE id IN rarray(?1) AND (?2 IS NULL OR location = ?2)
+                )",
+            )?
+            .query_row((id_array(&ids), location), |row| row.get(0))?;
+        let user = has_logs
+            .then(|| Self::load_in(conn, clock, ids[0], location))
+            .transpose()?;
+        Ok(user.filter(|user| !user.hidden))
+    }
+
+    /// Registers a beep of `card` on the reader in `location`, returning the ID it is stored under
+    pub fn register(
+        conn: &Connection,
+        clock: &dyn Clock,
+        card: u32,
+        location: &str,
+    ) -> rusqlite::Result<u64> {
+        let uid = stored_id(card);
+        let now = clock.now();
+        let date = rollover_date(now);
+        conn.prepare_cached(
+            "INSERT INTO logs (id, timestamp, date, location) VALUES (?1, ?2, ?3, ?4)",
         )
-        .unwrap();
+        .and_then(|mut stmt| stmt.execute((&uid, &now, &date, location)))
+        .inspect_err(|_| metrics::db_error())?;
+        metrics::beep();
+        Ok(uid)
     }
 
-    pub fn set_username(&self, username: &str) {
-        let conn = get_db();
+    pub fn set_username(&self, conn: &Connection, clock: &dyn Clock, username: &str) {
         conn.execute(
-            "INSERT INTO people (id, username) VALUES (?1, ?2) 
-                    ON CONFLICT (id) DO UPDATE SET username=excluded.username",
-            (&self.id, username),
+            "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3) 
+                    ON CONFLICT (id) DO UPDATE SET username=excluded.username, changed=excluded.changed",
+            (&self.id, username, clock.now()),
         )
         .unwrap();
     }
+
+    /// Hides or shows the profile of every card of the user
+    pub fn set_hidden(&self, conn: &Connection, clock: &dyn Clock, hidden: bool) {
+        let now = clock.now();
+        for id in &self.ids {
+            set_visibility(conn, *id, hidden, now).unwrap();
+        }
+    }
+}
+
+/// Whether any of the cards `ids` has its profile hidden
+pub fn is_hidden(conn: &Connection, ids: &[u64]) -> rusqlite::Result<bool> {
+    conn.prepare_cached(
+        "SELECT EXISTS (SELECT 1 FROM visibility WHERE hidden AND id IN rarray(?1))",
+    )?
+    .query_row([id_array(ids)], |row| row.get(0))
+}
+
+/// Stores whether the profile of card `id` is hidden, unless it was changed after `changed`
+pub fn set_visibility(
+    conn: &Connection,
+    id: u64,
+    hidden: bool,
+    changed: DateTime<Utc>,
+) -> rusqlite::Result<usize> {
+    conn.prepare_cached(
+        "INSERT INTO visibility (id, hidden, changed) VALUES (?1, ?2, ?3)
+        ON CONFLICT (id) DO UPDATE SET hidden = excluded.hidden, changed = excluded.changed
+        WHERE excluded.changed > visibility.changed",
+    )?
+    .execute((id, hidden, changed))
+}
+
+/// Card IDs registered on `username`.
+/// Users that never set a username are looked up by their card ID.
+pub fn ids_for_username(conn: &Connection, username: &str) -> rusqlite::Result<Vec<u64>> {
+    let ids = get_ids(conn, username)?;
+    Ok(match username.parse() {
+        _ if !ids.is_empty() => ids,
+        Ok(uid) => vec![uid],
+        Err(_) => vec![],
+    })
+}
+
+pub fn get_ids(conn: &Connection, username: &str) -> rusqlite::Result<Vec<u64>> {
+    let mut ids_stmt = conn.prepare_cached("SELECT id FROM people WHERE USERNAME=($1)")?;
+
+    let ids = ids_stmt.query_map([username], |row| row.get(0))?;
+    ids.collect()
 }
 
 #[derive(Debug)]
 pub struct Stats {
     pub streak: usize,
+    /// The streak ends unless the user comes today, since they have not beeped yet
+    pub streak_at_risk: bool,
     pub longest_day: Day,
-    pub today: Day,
+    /// The day of the latest visit, which is today right after a beep
+    pub latest: Day,
     pub earliest_arrival: Day,
     pub latest_departure: Day,
-    pub days: Vec<DayOrDate>,
-    pub days_milliseconds: Vec<Option<u64>>,
+    /// Registered days, newest first
+    pub days: Vec<Day>,
+    /// Date the streak and day counts are counted up to
+    pub as_of: NaiveDate,
     pub last_week_count: usize,
     pub last_month_count: usize,
 }
 
 impl Stats {
-    fn load_for_user(ids: &[u32]) -> Self {
-        let conn = get_db();
-
-        let days = get_days(ids, conn);
-        let day_or_dates = days.as_slice().iter_option();
-        let streak = get_streak(&day_or_dates);
-        let today = days[0];
+    /// Stats with streaks and day counts up to `today`
+    fn load_for_user(
+        conn: &Connection,
+        today: NaiveDate,
+        ids: &[u64],
+        location: Option<&str>,
+    ) -> rusqlite::Result<Self> {
+        let days = get_days(conn, ids, location)?;
+        assert!(
+            !days.is_empty(),
+            "Since this only runs after inserting a day, days should never be empty"
+        );
+        // Today is not over, so not having beeped yet only puts the streak at risk
+        let mut dates = calendar(&days, today).peekable();
+        let unregistered = dates.next_if(|day| !day.is_registered()).is_some();
+        let streak = get_streak(dates);
+        let streak_at_risk = unregistered && !is_weekend(today) && streak > 0;
+        let last_week_count = get_last_n(7, calendar(&days, today));
+        let last_month_count = get_last_n(30, calendar(&days, today));
+        let latest = days[0];
         let longest_day = get_longest_day(&days);
         let earliest_arrival = get_earliest(&days);
         let latest_departure = get_latest(&days);
-        let days_milliseconds = get_milliseconds(&day_or_dates);
-        let last_week_count = get_last_n(7, &day_or_dates);
-        let last_month_count = get_last_n(30, &day_or_dates);
 
-        Self {
+        Ok(Self {
             streak,
+            streak_at_risk,
             longest_day,
-            today,
+            latest,
             earliest_arrival,
             latest_departure,
-            days: day_or_dates,
-            days_milliseconds,
+            days,
+            as_of: today,
             last_week_count,
             last_month_count,
-        }
+        })
     }
 }
 
-fn get_days(ids: &[u32], conn: Connection) -> Vec<Day> {
-    assert!(ids.len() != 0, "Cannot get the days of nobody");
-    let placeholders = std::iter::repeat("?")
-        .take(ids.len())
-        .collect::<Vec<_>>()
-        .join(",");
+/// Days of the card IDs, newest first, counting only beeps in `location` if given
+pub fn get_days(
+    conn: &Connection,
+    ids: &[u64],
+    location: Option<&str>,
+) -> rusqlite::Result<Vec<Day>> {
+    assert!(!ids.is_empty(), "Cannot get the days of nobody");
 
     let query = "
     SELECT
         date,
         MIN(timestamp) AS first_timestamp,
         MAX(timestamp) AS last_timestamp,
-        JULIANDAY(MAX(timestamp)) - JULIANDAY(MIN(timestamp)) AS difference_in_days
+        JULIANDAY(MAX(timestamp)) - JULIANDAY(MIN(timestamp)) AS difference_in_days,
+        SUM(beeps) AS beeps,
+        (
+            SELECT COUNT(*) FROM coffee
+            WHERE coffee.id IN rarray(?1) AND coffee.date = logs.date
+        ) AS coffee
     FROM
         logs
     WHERE
-        id IN rarray(?)
+        id IN rarray(?1)
+        AND (?2 IS NULL OR location = ?2)
     GROUP BY
         date
     ORDER BY
         date DESC
     ";
 
-    let mut stmt = conn.prepare(query).unwrap();
-    let ids = ids.iter().copied().map(Value::from).collect_vec();
-    let ids = Rc::new(ids);
-    let days = stmt
-        .query_map([ids], |row| {
-            let date: NaiveDate = row.get(0).unwrap();
-            let start: DateTime<Utc> = row.get(1).unwrap();
-            let end: DateTime<Utc> = row.get(2).unwrap();
+    let mut stmt = conn.prepare_cached(query)?;
+    let days = stmt.query_map((id_array(ids), location), |row| {
+        let date: NaiveDate = row.get(0)?;
+        let start: DateTime<Utc> = row.get(1)?;
+        let end: DateTime<Utc> = row.get(2)?;
+        let beeps: u32 = row.get(4)?;
+        let coffee: u32 = row.get(5)?;
+
+        Ok(Day::new(date, start, end, beeps, coffee))
+    })?;
+    days.collect()
+}
 
-            Ok(Day::new(date, start, end))
-        })
+/// Logs with the name of their owner: the username, or the card ID if no username is set.
+/// Users who hid their profile are left out.
+const OWNED_LOGS: &str = "hidden_owners AS (
+        SELECT COALESCE(people.username, CAST(visibility.id AS TEXT)) AS owner
+        FROM visibility LEFT JOIN people ON people.id = visibility.id
+        WHERE visibility.hidden
+    ),
+    owned_logs AS (
+        SELECT logs.*, COALESCE(people.username, CAST(logs.id AS TEXT)) AS owner
+        FROM logs LEFT JOIN people ON people.id = logs.id
+        WHERE COALESCE(people.username, CAST(logs.id AS TEXT))
+            NOT IN (SELECT owner FROM hidden_owners)
+    )";
+
+/// Every user with the number of days they have been at the reading room and their last beep,
+/// ordered by name
+pub fn get_users(conn: &Connection) -> Vec<(String, usize, DateTime<Utc>)> {
+    let query = format!(
+        "
+    WITH {OWNED_LOGS}
+    SELECT owner, COUNT(DISTINCT date), MAX(timestamp)
+    FROM owned_logs
+    GROUP BY owner
+    ORDER BY owner ASC
+    "
+    );
+
+    let mut stmt = conn.prepare_cached(&query).unwrap();
+    let users = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
         .unwrap();
-    let days: Result<Vec<_>, _> = days.collect();
+    let users: Result<Vec<_>, _> = users.collect();
+    users.unwrap()
+}
+
+/// Users currently in a reading room on `date`, ordered by name.
+/// Everyone beeps in and out, so an odd number of beeps in the room of the last beep means the
+/// user has not left it yet. A beep in another room counts as leaving the previous one.
+pub fn get_occupancy(
+    conn: &Connection,
+    date: NaiveDate,
+    location: Option<&str>,
+) -> rusqlite::Result<Vec<String>> {
+    let query = format!(
+        "
+    WITH {OWNED_LOGS},
+    latest AS (
+        -- SQLite takes the bare location column from the row with the latest timestamp
+        SELECT owner, location, MAX(timestamp)
+        FROM owned_logs
+        WHERE date = ?1
+        GROUP BY owner
+    )
+    SELECT owner FROM latest
+    WHERE (?2 IS NULL OR location = ?2)
+    AND (
+        -- Beeps in the room since the last beep in another room
+        SELECT SUM(beeps) FROM owned_logs AS room
+        WHERE room.owner = latest.owner AND room.date = ?1
+        AND room.location = latest.location
+        AND room.timestamp > COALESCE((
+            SELECT MAX(other.timestamp) FROM owned_logs AS other
+            WHERE other.owner = latest.owner AND other.date = ?1
+            AND other.location != latest.location
+        ), '')
+    ) % 2 = 1
+    ORDER BY owner ASC
+    "
+    );
 
-    let days = days.unwrap();
+    let mut stmt = conn.prepare_cached(&query)?;
+    let owners = stmt.query_map((date, location), |row| row.get(0))?;
+    owners.collect()
+}
 
-    assert!(
-        days.len() != 0,
-        "Since this only runs after inserting a day, days should never be empty"
+/// Users ranked by number of days between `from` and `to`, with total time spent as tiebreaker
+pub fn get_leaderboard(
+    conn: &Connection,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    location: Option<&str>,
+) -> rusqlite::Result<Vec<(String, usize, TimeDelta)>> {
+    Ok(get_all_days(conn, from, to, None, location)?
+        .into_iter()
+        .into_group_map()
+        .into_iter()
+        .map(|(owner, days)| {
+            let total = days.iter().map(Day::span).sum();
+            (owner, days.len(), total)
+        })
+        .sorted_by(|a, b| (b.1, b.2, &a.0).cmp(&(a.1, a.2, &b.0)))
+        .collect())
+}
+
+/// Every room beeps have been registered in, with the number of beeps
+pub fn get_locations(conn: &Connection) -> rusqlite::Result<Vec<(String, usize)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT location, SUM(beeps) FROM logs GROUP BY location ORDER BY location ASC",
+    )?;
+    let locations = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    locations.collect()
+}
+
+/// Days of every user between `from` and `to`, inclusive, ordered by date.
+/// Card IDs sharing a username are merged, IDs without a username are named by the ID.
+/// Only beeps in `location` are counted if given.
+pub fn get_all_days(
+    conn: &Connection,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    username: Option<&str>,
+    location: Option<&str>,
+) -> rusqlite::Result<Vec<(String, Day)>> {
+    let query = format!(
+        "
+    WITH {OWNED_LOGS},
+    owned_coffee AS (
+        SELECT coffee.date, COALESCE(people.username, CAST(coffee.id AS TEXT)) AS owner
+        FROM coffee LEFT JOIN people ON people.id = coffee.id
+    )
+    SELECT
+        date,
+        owner,
+        MIN(timestamp) AS first_timestamp,
+        MAX(timestamp) AS last_timestamp,
+        SUM(beeps) AS beeps,
+        (
+            SELECT COUNT(*) FROM owned_coffee
+            WHERE owned_coffee.owner = owned_logs.owner AND owned_coffee.date = owned_logs.date
+        ) AS coffee
+    FROM
+        owned_logs
+    WHERE
+        (?1 IS NULL OR date >= ?1)
+        AND (?2 IS NULL OR date <= ?2)
+        AND (?3 IS NULL OR owner = ?3)
+        AND (?4 IS NULL OR location = ?4)
+    GROUP BY
+        owner, date
+    ORDER BY
+        date ASC, owner ASC
+    "
     );
-    days
+
+    let mut stmt = conn.prepare_cached(&query)?;
+    let days = stmt.query_map((from, to, username, location), |row| {
+        let date: NaiveDate = row.get(0)?;
+        let owner: String = row.get(1)?;
+        let start: DateTime<Utc> = row.get(2)?;
+        let end: DateTime<Utc> = row.get(3)?;
+        let beeps: u32 = row.get(4)?;
+        let coffee: u32 = row.get(5)?;
+
+        Ok((owner, Day::new(date, start, end, beeps, coffee)))
+    })?;
+    days.collect()
 }
 
 fn get_longest_day(days: &[Day]) -> Day {
     *days.iter().max_by_key(|day| day.span()).unwrap()
 }
 
-fn get_streak(days: &[DayOrDate]) -> usize {
-    days.iter()
-        .take_while(|day| match day {
-            DayOrDate::Unregistered(date) => {
-                date.weekday() == Weekday::Sun || date.weekday() == Weekday::Sat
-            }
-            DayOrDate::Registered(_) => true,
-        })
-        .filter(|day| day.is_registered())
-        .count()
+fn is_weekend(date: NaiveDate) -> bool {
+    date.weekday() == Weekday::Sun || date.weekday() == Weekday::Sat
+}
+
+/// Registered days in a row, where weekends without a visit do not break the streak
+fn get_streak(days: impl Iterator<Item = DayOrDate>) -> usize {
+    days.take_while(|day| match day {
+        DayOrDate::Unregistered(date) => is_weekend(*date),
+        DayOrDate::Registered(_) => true,
+    })
+    .filter(|day| day.is_registered())
+    .count()
 }
 
+/// Arrivals and departures are compared by wall-clock time, from 05:00 to 04:59
 fn get_earliest(days: &[Day]) -> Day {
     *days
-        .into_iter()
-        .min_by_key(|d| (d.start - TimeDelta::hours(5)).time())
+        .iter()
+        .min_by_key(|d| d.start.time() - TimeDelta::hours(5))
         .unwrap()
 }
 
 fn get_latest(days: &[Day]) -> Day {
     *days
-        .into_iter()
-        .max_by_key(|d| (d.end - TimeDelta::hours(5)).time())
+        .iter()
+        .max_by_key(|d| d.end.time() - TimeDelta::hours(5))
         .unwrap()
 }
 
-fn get_milliseconds(days: &[DayOrDate]) -> Vec<Option<u64>> {
-    days.iter()
-        .map(|day| match day {
-            DayOrDate::Unregistered(_) => None,
-            DayOrDate::Registered(day) => Some(day.span().num_milliseconds() as u64),
-        })
-        .collect()
-}
-
-fn get_last_n(n: usize, days: &[DayOrDate]) -> usize {
-    days.iter()
-        .take(n)
-        .filter(|day| day.is_registered())
-        .count()
+fn get_last_n(n: usize, days: impl Iterator<Item = DayOrDate>) -> usize {
+    days.take(n).filter(|day| day.is_registered()).count()
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -234,13 +531,29 @@ pub struct Day {
     pub date: NaiveDate,
     pub start: DateTime<Tz>,
     pub end: DateTime<Tz>,
+    /// Number of beeps registered during the day
+    pub beeps: u32,
+    /// Number of coffees registered during the day
+    pub coffee: u32,
 }
 
 impl Day {
-    pub fn new(date: NaiveDate, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+    pub fn new(
+        date: NaiveDate,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        beeps: u32,
+        coffee: u32,
+    ) -> Self {
         let start = start.with_timezone(&Oslo);
         let end = end.with_timezone(&Oslo);
-        Self { date, start, end }
+        Self {
+            date,
+            start,
+            end,
+            beeps,
+            coffee,
+        }
     }
 
     pub fn stats(&self) -> DayStats {
@@ -284,14 +597,14 @@ pub enum DayOrDate {
 }
 
 impl DayOrDate {
-    fn date(&self) -> NaiveDate {
+    pub fn date(&self) -> NaiveDate {
         match self {
-            &DayOrDate::Registered(day) => day.date,
-            &DayOrDate::Unregistered(date) => date,
+            DayOrDate::Registered(day) => day.date,
+            DayOrDate::Unregistered(date) => *date,
         }
     }
 
-    fn is_registered(&self) -> bool {
+    pub fn is_registered(&self) -> bool {
         match self {
             DayOrDate::Registered(_) => true,
             DayOrDate::Unregistered(_) => false,
@@ -299,29 +612,207 @@ impl DayOrDate {
     }
 }
 
-pub trait DayVec {
-    fn iter_option(&self) -> Vec<DayOrDate>;
+/// Every date from `today` back to the first registered day, newest first, with the days of
+/// `days` where registered. `days` must be newest first like from `get_days`, and days after
+/// `today` are left out.
+pub fn calendar(days: &[Day], today: NaiveDate) -> Calendar<'_> {
+    Calendar {
+        days: days.iter().peekable(),
+        date: today,
+        first: days.last().map(|day| day.date),
+    }
 }
 
-impl DayVec for &[Day] {
-    fn iter_option(&self) -> Vec<DayOrDate> {
-        let n_days = (self[0].date - self.last().unwrap().date).num_days() + 1;
-        let mut output = Vec::with_capacity(n_days as usize);
-        for (day, prev_day) in self.iter().tuple_windows() {
-            output.push(DayOrDate::Registered(*day));
-            let day = day.date;
-            let prev_day = prev_day.date;
-            if day == prev_day {
-                continue;
-            }
+#[derive(Debug, Clone)]
+pub struct Calendar<'a> {
+    days: Peekable<slice::Iter<'a, Day>>,
+    /// Next date to yield
+    date: NaiveDate,
+    first: Option<NaiveDate>,
+}
 
-            let mut btwn = day - TimeDelta::days(1);
-            while btwn != prev_day {
-                output.push(DayOrDate::Unregistered(btwn));
+impl Iterator for Calendar<'_> {
+    type Item = DayOrDate;
 
-                btwn -= TimeDelta::days(1);
-            }
+    fn next(&mut self) -> Option<DayOrDate> {
+        let date = self.date;
+        if date < self.first? {
+            return None;
         }
-        output
+        while self.days.next_if(|day| day.date > date).is_some() {}
+        let item = match self.days.next_if(|day| day.date == date) {
+            Some(day) => DayOrDate::Registered(*day),
+            None => DayOrDate::Unregistered(date),
+        };
+        self.date = date.pred_opt()?;
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use itertools::Itertools;
+
+    use super::{
+        calendar, get_days, get_earliest, get_latest, get_occupancy, get_streak, Day, Person,
+    };
+    use crate::clock::FixedClock;
+    use crate::fixtures::{oslo, Fixture};
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    /// Beeps card 1234567890 at each time in Oslo and returns the days they count towards
+    fn days_of(beeps: &[&str]) -> Vec<Day> {
+        let conn = Fixture::new().beeps(1234567890, beeps).build();
+        get_days(&conn, &[1234567890], None).unwrap()
+    }
+
+    /// Two beeps at 08:00
+    fn visit(date: NaiveDate) -> Day {
+        let start = oslo(&format!("{date} 08:00"));
+        Day::new(date, start, start, 2, 0)
+    }
+
+    #[test]
+    fn days_roll_over_at_five() {
+        let days = days_of(&[
+            "2025-02-10 08:00",
+            // Still monday
+            "2025-02-11 04:59",
+            "2025-02-11 05:00",
+            "2025-02-11 23:00",
+        ]);
+        let dates = days.iter().map(|day| day.date).collect_vec();
+        assert_eq!(dates, [date(2025, 2, 11), date(2025, 2, 10)]);
+        // 05:00 is the earliest arrival possible and 04:59 the latest departure
+        assert_eq!(get_earliest(&days).date, date(2025, 2, 11));
+        assert_eq!(get_latest(&days).date, date(2025, 2, 10));
+    }
+
+    #[test]
+    fn days_roll_over_at_five_when_clocks_change() {
+        // Summer time starts at 02:00 on 30 March 2025 and ends at 03:00 on 26 October
+        let days = days_of(&[
+            "2025-03-30 04:59",
+            "2025-03-30 05:00",
+            "2025-10-26 04:59",
+            "2025-10-26 05:00",
+        ]);
+        let dates = days.iter().map(|day| day.date).collect_vec();
+        assert_eq!(
+            dates,
+            [
+                date(2025, 10, 26),
+                date(2025, 10, 25),
+                date(2025, 3, 30),
+                date(2025, 3, 29)
+            ]
+        );
+
+        // Arrival and departure times are compared on the wall clock
+        let days = days_of(&[
+            "2025-03-28 05:45",
+            "2025-03-28 23:45",
+            "2025-03-30 05:30",
+            "2025-03-30 23:30",
+        ]);
+        assert_eq!(get_earliest(&days).date, date(2025, 3, 30));
+        assert_eq!(get_latest(&days).date, date(2025, 3, 28));
+    }
+
+    #[test]
+    fn streaks_skip_weekends_but_not_weekdays() {
+        let days = [
+            visit(date(2025, 2, 10)),
+            visit(date(2025, 2, 7)),
+            visit(date(2025, 2, 6)),
+            // No visit on wednesday
+            visit(date(2025, 2, 4)),
+            visit(date(2025, 2, 3)),
+        ];
+        assert_eq!(get_streak(calendar(&days, date(2025, 2, 10))), 3);
+    }
+
+    #[test]
+    fn calendar_runs_from_today_to_the_first_visit() {
+        let days = [visit(date(2025, 2, 12)), visit(date(2025, 2, 10))];
+        let dates = |today| {
+            calendar(&days, today)
+                .map(|day| (day.date(), day.is_registered()))
+                .collect_vec()
+        };
+        // Today is included before it is registered
+        assert_eq!(
+            dates(date(2025, 2, 13)),
+            [
+                (date(2025, 2, 13), false),
+                (date(2025, 2, 12), true),
+                (date(2025, 2, 11), false),
+                (date(2025, 2, 10), true),
+            ]
+        );
+        // Days after today are left out
+        assert_eq!(
+            dates(date(2025, 2, 11)),
+            [(date(2025, 2, 11), false), (date(2025, 2, 10), true)]
+        );
+
+        assert_eq!(calendar(&[], date(2025, 2, 10)).count(), 0);
+    }
+
+    #[test]
+    fn streaks_are_counted_up_to_today() {
+        // Thursday to tuesday, with the weekend off
+        let conn = Fixture::new()
+            .beeps(
+                1234567890,
+                &[
+                    "2025-02-06 08:00",
+                    "2025-02-07 08:00",
+                    "2025-02-10 08:00",
+                    "2025-02-11 08:00",
+                ],
+            )
+            .build();
+        let stats = |now| {
+            let clock = FixedClock::new(oslo(now));
+            let stats = Person::load(&conn, &clock, 1234567890).unwrap().stats;
+            (
+                stats.streak,
+                stats.streak_at_risk,
+                stats.last_week_count,
+                stats.last_month_count,
+            )
+        };
+        assert_eq!(stats("2025-02-11 09:00"), (4, false, 4, 4));
+        // Not beeped in yet on wednesday
+        assert_eq!(stats("2025-02-12 09:00"), (4, true, 4, 4));
+        // Nor on thursday, after missing wednesday
+        assert_eq!(stats("2025-02-13 09:00"), (0, false, 3, 4));
+        // Weekends do not put the streak at risk
+        assert_eq!(stats("2025-02-08 09:00"), (2, false, 2, 2));
+        assert_eq!(stats("2025-03-04 09:00"), (0, false, 0, 4));
+    }
+
+    #[test]
+    fn beeps_in_another_room_leave_the_previous_one() {
+        let conn = Fixture::new()
+            .user("ola", &[1])
+            .user("kari", &[2])
+            .user("per", &[3])
+            .beeps_in("sal", 1, &["2025-02-11 08:00"])
+            .beeps_in("kjeller", 1, &["2025-02-11 09:00"])
+            .beeps_in("sal", 2, &["2025-02-11 08:00", "2025-02-11 09:00"])
+            .beeps_in("kjeller", 2, &["2025-02-11 10:00"])
+            .beeps_in("sal", 3, &["2025-02-11 08:00"])
+            .beeps_in("kjeller", 3, &["2025-02-11 09:00", "2025-02-11 10:00"])
+            .build();
+        let present = |location| get_occupancy(&conn, date(2025, 2, 11), location).unwrap();
+        assert_eq!(present(Some("kjeller")), ["kari", "ola"]);
+        assert!(present(Some("sal")).is_empty());
+        assert_eq!(present(None), ["kari", "ola"]);
     }
 }
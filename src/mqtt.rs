@@ -0,0 +1,157 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use json::JsonValue;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+use crate::config::MqttConfig;
+use crate::models::Person;
+
+/// Wait before reconnecting after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Remote commands received on `<prefix>/command`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Log out the user shown on screen
+    Clear,
+    /// Read `sal.json` again
+    Reload,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "clear" => Ok(Command::Clear),
+            "reload" => Ok(Command::Reload),
+            _ => Err(format!("Unknown command {s:?}, expected clear or reload")),
+        }
+    }
+}
+
+/// Publishes presence to the broker. Publishing never blocks: messages are dropped if the
+/// connection falls behind, as the next beep publishes the current occupancy anyway.
+pub struct Mqtt {
+    client: Client,
+    prefix: String,
+}
+
+impl fmt::Debug for Mqtt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mqtt")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mqtt {
+    /// Connects from a background thread, which also passes on commands to the returned receiver
+    pub fn connect(config: &MqttConfig) -> (Self, Receiver<Command>) {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, mut connection) = Client::new(options, 64);
+
+        let (tx, rx) = mpsc::channel();
+        let command_topic = format!("{}/command", config.prefix);
+        let subscriber = client.clone();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    // Subscribe on every connect, as the broker forgets subscriptions on reconnect
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        let _ = subscriber.try_subscribe(&command_topic, QoS::AtLeastOnce);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish)))
+                        if publish.topic == command_topic =>
+                    {
+                        let payload = String::from_utf8_lossy(&publish.payload);
+                        if let Ok(command) = payload.parse() {
+                            if tx.send(command).is_err() {
+                                // The app has exited
+                                return;
+                            }
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(_) => thread::sleep(RECONNECT_DELAY),
+                }
+            }
+        });
+
+        let mqtt = Self {
+            client,
+            prefix: config.prefix.clone(),
+        };
+        (mqtt, rx)
+    }
+
+    /// Publishes the beep to `<prefix>/presence` and the occupancy to `<prefix>/occupancy`.
+    /// The occupancy is retained, so the door sign gets it as soon as it connects.
+    pub fn publish_beep(&self, user: &Person, occupancy: usize) {
+        let _ = self.client.try_publish(
+            format!("{}/presence", self.prefix),
+            QoS::AtLeastOnce,
+            false,
+            json::stringify(presence(user)),
+        );
+        let _ = self.client.try_publish(
+            format!("{}/occupancy", self.prefix),
+            QoS::AtLeastOnce,
+            true,
+            occupancy.to_string(),
+        );
+    }
+}
+
+/// Users beep in and out, so an odd number of beeps today means the user is present
+fn presence(user: &Person) -> JsonValue {
+    // Only published right after a beep, so the latest day is today
+    let today = &user.stats.latest;
+    let present = today.beeps % 2 == 1;
+    json::object! {
+        username: user.username.as_str(),
+        event: if present { "arrival" } else { "departure" },
+        present: present,
+        timestamp: today.end.to_rfc3339(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{presence, Command};
+    use crate::clock::SystemClock;
+    use crate::fixtures::Fixture;
+    use crate::models::Person;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!("clear".parse(), Ok(Command::Clear));
+        assert_eq!("reload\n".parse(), Ok(Command::Reload));
+        assert!("restart".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn presence_follows_beeps() {
+        let times = ["2025-02-10 08:00", "2025-02-10 16:00"];
+        let presence_after = |beeps| {
+            let conn = Fixture::new().beeps(1234567890, &times[..beeps]).build();
+            presence(&Person::load(&conn, &SystemClock, 1234567890).unwrap())
+        };
+
+        let arrived = presence_after(1);
+        assert_eq!(arrived["event"], "arrival");
+        assert_eq!(arrived["present"], true);
+
+        let left = presence_after(2);
+        assert_eq!(left["event"], "departure");
+        assert_eq!(left["present"], false);
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use itertools::Itertools;
+use json::JsonValue;
+use rusqlite::{Connection, OptionalExtension, Statement, ToSql};
+
+use crate::migrate::create_tables;
+use crate::models::{get_ids, id_array, open_db};
+use crate::pseudonym::card_id;
+use crate::snapshot::value_to_json;
+
+/// Every table holding personal data, with the condition picking out a person's rows.
+/// `?1` is the person's card IDs and `?2` their username, if they have one.
+const PERSONAL_DATA: [(&str, &str); 7] = [
+    ("people", "id IN rarray(?1)"),
+    ("visibility", "id IN rarray(?1)"),
+    ("imported_users", "id IN rarray(?1)"),
+    ("logs", "id IN rarray(?1)"),
+    ("coffee", "id IN rarray(?1)"),
+    // Malformed lines from the prototype's files, by the card ID found in them
+    ("import_quarantine", "id IN rarray(?1)"),
+    // Deliveries not yet sent, by the user they are about. Deliveries queued before this was
+    // stored are found by the username in the default payload.
+    (
+        "webhook_queue",
+        "user_id IN rarray(?1)
+        OR (user_id IS NULL AND json_extract(body, '$.username') = ?2)",
+    ),
+];
+
+/// The card IDs and username of whoever holds `card`.
+/// Every card registered on the same username belongs to the same person.
+fn person(conn: &Connection, card: u32) -> (Vec<u64>, Option<String>) {
+    let card = card_id(card.into());
+    let username: Option<String> = conn
+        .query_row("SELECT username FROM people WHERE id = ?1", [card], |row| {
+            row.get(0)
+        })
+        .optional()
+        .unwrap();
+    let mut ids = match &username {
+        Some(username) => get_ids(conn, username).unwrap(),
+        None => vec![],
+    };
+    if !ids.contains(&card) {
+        ids.push(card);
+    }
+    (ids, username)
+}
+
+/// Prepares `sql` for one of the conditions, and binds the parameters it uses
+fn prepare<'a>(
+    conn: &'a Connection,
+    sql: &str,
+    ids: &[u64],
+    username: &Option<String>,
+) -> rusqlite::Result<Statement<'a>> {
+    let ids = id_array(ids);
+    let params: [&dyn ToSql; 2] = [&ids, username];
+    let mut stmt = conn.prepare(sql)?;
+    for (i, param) in params.iter().enumerate().take(stmt.parameter_count()) {
+        stmt.raw_bind_parameter(i + 1, param)?;
+    }
+    Ok(stmt)
+}
+
+/// The rows of every table about whoever holds `card`, as objects keyed by column
+fn bundle(conn: &Connection, card: u32) -> JsonValue {
+    let (ids, username) = person(conn, card);
+    let mut tables = JsonValue::new_object();
+    for (table, condition) in PERSONAL_DATA {
+        let sql = format!("SELECT * FROM {table} WHERE {condition}");
+        let mut stmt = prepare(conn, &sql, &ids, &username).unwrap();
+        let columns = stmt
+            .column_names()
+            .into_iter()
+            .map(str::to_string)
+            .collect_vec();
+        let mut rows = stmt.raw_query();
+        let mut objects = vec![];
+        while let Some(row) = rows.next().unwrap() {
+            let mut object = JsonValue::new_object();
+            for (i, column) in columns.iter().enumerate() {
+                object[column.as_str()] = value_to_json(row.get_ref(i).unwrap());
+            }
+            objects.push(object);
+        }
+        tables[table] = objects.into();
+    }
+    json::object! {
+        exported: Utc::now().to_rfc3339(),
+        cards: ids,
+        username: username,
+        tables: tables,
+    }
+}
+
+/// Number of rows in each table about whoever holds `card`
+fn count(conn: &Connection, card: u32) -> Vec<(&'static str, usize)> {
+    let (ids, username) = person(conn, card);
+    PERSONAL_DATA
+        .iter()
+        .map(|(table, condition)| {
+            let sql = format!("SELECT COUNT(*) FROM {table} WHERE {condition}");
+            let mut stmt = prepare(conn, &sql, &ids, &username).unwrap();
+            let n = stmt.raw_query().next().unwrap().unwrap().get(0).unwrap();
+            (*table, n)
+        })
+        .collect()
+}
+
+fn audit(conn: &Connection, action: &str, counts: &[(&str, usize)], reason: Option<&str>) {
+    let mut rows = JsonValue::new_object();
+    for (table, n) in counts {
+        rows[*table] = (*n).into();
+    }
+    conn.execute(
+        "INSERT INTO privacy_audit (timestamp, action, rows, reason) VALUES (?1, ?2, ?3, ?4)",
+        (Utc::now(), action, json::stringify(rows), reason),
+    )
+    .unwrap();
+}
+
+/// Deletes every row about whoever holds `card`, returning the number of rows per table.
+/// Deleted rows are overwritten in the database file, so they cannot be recovered from it.
+fn erase_rows(
+    conn: &Connection,
+    card: u32,
+    reason: Option<&str>,
+) -> rusqlite::Result<Vec<(&'static str, usize)>> {
+    let (ids, username) = person(conn, card);
+    conn.pragma_update(None, "secure_delete", true)?;
+    let tx = conn.unchecked_transaction()?;
+    let mut counts = vec![];
+    for (table, condition) in PERSONAL_DATA {
+        let sql = format!("DELETE FROM {table} WHERE {condition}");
+        let n = prepare(&tx, &sql, &ids, &username)?.raw_execute()?;
+        counts.push((table, n));
+    }
+    audit(&tx, "erase", &counts, reason);
+    tx.commit()?;
+    // Move the overwritten pages out of the write-ahead log as well
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(counts)
+}
+
+fn print_counts(counts: &[(&str, usize)]) {
+    for (table, n) in counts {
+        println!("  {table}: {n} rows");
+    }
+}
+
+/// Writes everything stored about whoever holds `card` to a JSON file
+pub fn export(db: &Path, card: u32, output: Option<PathBuf>) -> io::Result<()> {
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    let counts = count(&conn, card);
+    if counts.iter().all(|(_, n)| *n == 0) {
+        return Err(io::Error::other(format!(
+            "Found nothing stored about card {card}"
+        )));
+    }
+
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("privacy-{card}.json")));
+    fs::write(&output, json::stringify_pretty(bundle(&conn, card), 2))?;
+    audit(&conn, "export", &counts, None);
+    println!("Wrote to {}:", output.display());
+    print_counts(&counts);
+    Ok(())
+}
+
+/// Deletes everything stored about whoever holds `card`, or only shows what would be deleted
+pub fn erase(db: &Path, card: u32, dry_run: bool, reason: Option<&str>) -> io::Result<()> {
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    if dry_run {
+        println!("Would erase:");
+        print_counts(&count(&conn, card));
+        println!("Nothing was erased, run again without --dry-run to erase");
+        return Ok(());
+    }
+
+    let counts = erase_rows(&conn, card, reason).map_err(io::Error::other)?;
+    println!("Erased:");
+    print_counts(&counts);
+    println!("Backups, snapshots and central servers synced to still hold the data");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bundle, count, erase_rows};
+    use crate::clock::SystemClock;
+    use crate::config::WebhookConfig;
+    use crate::events::Event;
+    use crate::fixtures::Fixture;
+    use crate::models::Person;
+    use crate::pseudonym::redact;
+    use crate::webhooks::enqueue;
+
+    #[test]
+    fn every_card_of_a_person_is_exported_and_erased() {
+        // Two cards belonging to the same person
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-11 08:00"])
+            .beeps(1234567891, &["2025-02-11 09:00"])
+            .beeps(1234567892, &["2025-02-11 10:00"])
+            .user("ola", &[1234567890, 1234567891])
+            .build();
+        conn.execute(
+            "INSERT INTO coffee (id, timestamp, date) VALUES (1234567891, '2026-10-18T08:00:00Z', '2026-10-18')",
+            (),
+        )
+        .unwrap();
+        let (line, id) = redact("i går;1234567890");
+        conn.execute(
+            "INSERT INTO import_quarantine (path, line_number, line, error, id)
+            VALUES ('20250211.csv', 1, ?1, 'Expected a comma', ?2)",
+            (line, id),
+        )
+        .unwrap();
+
+        let exported = bundle(&conn, 1234567890);
+        assert_eq!(exported["username"], "ola");
+        assert_eq!(exported["tables"]["logs"].len(), 2);
+        assert_eq!(exported["tables"]["people"].len(), 2);
+        assert_eq!(exported["tables"]["coffee"][0]["id"], 1234567891);
+        assert_eq!(exported["tables"]["import_quarantine"].len(), 1);
+
+        let erased = erase_rows(&conn, 1234567891, Some("Asked by email")).unwrap();
+        assert!(erased.contains(&("logs", 2)));
+        assert!(erased.contains(&("import_quarantine", 1)));
+        assert!(count(&conn, 1234567890).iter().all(|(_, n)| *n == 0));
+        // Other people are kept
+        assert!(count(&conn, 1234567892).contains(&("logs", 1)));
+
+        let (action, rows): (String, String) = conn
+            .query_row("SELECT action, rows FROM privacy_audit", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(action, "erase");
+        assert!(!rows.contains("123456789"));
+    }
+
+    #[test]
+    fn queued_deliveries_are_matched_by_user() {
+        // Names containing each other, and a user without a name
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-11 08:00"])
+            .beeps(1234567891, &["2025-02-11 09:00"])
+            .beeps(1234567892, &["2025-02-11 10:00"])
+            .user("ola", &[1234567890])
+            .user("carola", &[1234567891])
+            .build();
+        let webhooks = [WebhookConfig {
+            url: "http://localhost".to_string(),
+            events: vec![],
+            secret: None,
+            template: Some(json::object! { text: "{username} kom" }),
+        }];
+        for uid in [1234567890, 1234567891, 1234567892] {
+            let user = Person::load(&conn, &SystemClock, uid).unwrap();
+            enqueue(&conn, &SystemClock, &webhooks, &user, &[Event::Beep]).unwrap();
+        }
+
+        let erased = erase_rows(&conn, 1234567890, None).unwrap();
+        assert!(erased.contains(&("webhook_queue", 1)));
+        assert!(count(&conn, 1234567891).contains(&("webhook_queue", 1)));
+        let erased = erase_rows(&conn, 1234567892, None).unwrap();
+        assert!(erased.contains(&("webhook_queue", 1)));
+    }
+}
@@ -0,0 +1,302 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use rusqlite::{Connection, OptionalExtension};
+use sha2::Sha256;
+
+use crate::config::CardHashConfig;
+
+/// Written by `dump` next to `users.json` when the card IDs in the files are already hashed
+pub const HASHED_MARKER: &str = "card_ids_hashed";
+
+/// Card numbers are ten digits, so shorter numbers like the parts of a timestamp are left alone
+const CARD_DIGITS: usize = 7;
+
+/// Tables and their column with card IDs. Quarantined lines are rewritten separately.
+const TABLES: [(&str, &str); 6] = [
+    ("logs", "id"),
+    ("people", "id"),
+    ("coffee", "id"),
+    ("imported_users", "id"),
+    ("visibility", "id"),
+    ("webhook_queue", "user_id"),
+];
+
+/// Set once at startup when card IDs are hashed
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Reads the key, creating it if it does not exist, and hashes every card ID from now on
+pub fn init(config: &CardHashConfig) -> io::Result<()> {
+    let key = match fs::read_to_string(&config.key_file) {
+        Ok(key) => key,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => create_key(&config.key_file)?,
+        Err(err) => return Err(err),
+    };
+    let key = hex_decode(key.trim()).ok_or_else(|| {
+        io::Error::other(format!("{} is not a hex key", config.key_file.display()))
+    })?;
+    KEY.set(key)
+        .map_err(|_| io::Error::other("Card hashing is already set up"))
+}
+
+fn create_key(path: &Path) -> io::Result<String> {
+    let mut bytes = [0; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let key = hex(&bytes);
+    fs::write(path, &key)?;
+    // Anyone with the key can tell which card a hash belongs to
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    println!("Created a new card ID key in {}", path.display());
+    Ok(key)
+}
+
+/// The ID a card is stored under: a keyed hash of the card number, or the number itself
+/// when hashing is not configured
+pub fn card_id(card: u64) -> u64 {
+    match KEY.get() {
+        Some(key) => hash(key, card),
+        None => card,
+    }
+}
+
+/// The first 63 bits of the HMAC-SHA256 of the card number, the most an SQLite integer holds
+/// without going negative. Collisions are unlikely even with millions of cards.
+fn hash(key: &[u8], card: u64) -> u64 {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(card.to_string().as_bytes());
+    let bytes = mac.finalize().into_bytes();
+    u64::from_be_bytes(bytes[..8].try_into().unwrap()) >> 1
+}
+
+/// `line` with every number long enough to be a card number replaced by its stored ID, and
+/// the first of those IDs. Used for malformed lines kept by imports.
+pub fn redact(line: &str) -> (String, Option<u64>) {
+    redact_with(line, card_id)
+}
+
+fn redact_with(line: &str, card_id: impl Fn(u64) -> u64) -> (String, Option<u64>) {
+    let mut redacted = String::with_capacity(line.len());
+    let mut first = None;
+    let mut rest = line;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        redacted.push_str(&rest[..start]);
+        let digits = &rest[start..];
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        let number = &digits[..end];
+        match number.parse() {
+            Ok(card) if number.len() >= CARD_DIGITS => {
+                let id = card_id(card);
+                first.get_or_insert(id);
+                redacted.push_str(&id.to_string());
+            }
+            _ => redacted.push_str(number),
+        }
+        rest = &digits[end..];
+    }
+    redacted.push_str(rest);
+    (redacted, first)
+}
+
+/// Identifies the key and hash length without revealing the key, so a database is never
+/// mixed with another key or with the 32-bit hashes of earlier versions
+fn fingerprint(key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"sal:63");
+    hex(&mac.finalize().into_bytes())
+}
+
+/// Fingerprint of the key the card IDs in the database are hashed with, if they are
+pub fn stored_fingerprint(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    conn.query_row("SELECT fingerprint FROM card_hash", [], |row| row.get(0))
+        .optional()
+}
+
+/// Rewrites every card ID with the configured key the first time it is used with the database.
+/// Called by `create_tables`.
+pub fn check(conn: &Connection) -> io::Result<()> {
+    check_with(conn, KEY.get().map(Vec::as_slice))
+}
+
+fn check_with(conn: &Connection, key: Option<&[u8]>) -> io::Result<()> {
+    let hashed_with = stored_fingerprint(conn).map_err(io::Error::other)?;
+    match (key, hashed_with) {
+        (None, None) => {
+            fill_quarantine_ids(conn, |card| card).map_err(io::Error::other)?;
+        }
+        (None, Some(stored)) => {
+            return Err(io::Error::other(format!(
+                "The card IDs in the database are hashed with the key with fingerprint {stored}, \
+                configure card_hash in sal.json"
+            )))
+        }
+        (Some(key), Some(stored)) if fingerprint(key) != stored => {
+            return Err(io::Error::other(format!(
+                "The card IDs in the database were hashed with the key with fingerprint {stored}, \
+                but the configured key has fingerprint {}. It is another key, or the \
+                database was hashed by an older version of sal",
+                fingerprint(key)
+            )))
+        }
+        (Some(_), Some(_)) => (),
+        (Some(key), None) => {
+            let rows = rewrite(conn, key).map_err(io::Error::other)?;
+            if rows > 0 {
+                println!("Replaced the card IDs of {rows} rows with hashes");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces every card ID with its hash in one transaction, returning the number of rows changed
+fn rewrite(conn: &Connection, key: &[u8]) -> rusqlite::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut rows = 0;
+    for (table, column) in TABLES {
+        let ids: Vec<u64> = tx
+            .prepare(&format!(
+                "SELECT DISTINCT {column} FROM {table} WHERE {column} IS NOT NULL"
+            ))?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        // Hashes can equal card IDs not yet rewritten, so they are negated until every row is done
+        for id in ids {
+            rows += tx.execute(
+                &format!("UPDATE {table} SET {column} = ?2 WHERE {column} = ?1"),
+                (id, -1 - hash(key, id) as i64),
+            )?;
+        }
+        tx.execute(&format!("UPDATE {table} SET {column} = -1 - {column}"), ())?;
+    }
+    // Every quarantined line still has the card numbers in clear, so all are redacted again
+    tx.execute("UPDATE import_quarantine SET id = NULL", ())?;
+    rows += fill_quarantine_ids(&tx, |card| hash(key, card))?;
+    tx.execute(
+        "INSERT INTO card_hash (fingerprint) VALUES (?1)",
+        [fingerprint(key)],
+    )?;
+    tx.commit()?;
+    Ok(rows)
+}
+
+/// Sets the ID of quarantined lines without one, like those from before the `id` column
+/// existed, and replaces their card numbers when `card_id` hashes. Returns the rows changed.
+fn fill_quarantine_ids(conn: &Connection, card_id: impl Fn(u64) -> u64) -> rusqlite::Result<usize> {
+    let lines: Vec<(i64, String)> = conn
+        .prepare("SELECT rowid, line FROM import_quarantine WHERE id IS NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut rows = 0;
+    for (rowid, line) in lines {
+        let (redacted, id) = redact_with(&line, &card_id);
+        if redacted != line || id.is_some() {
+            rows += conn.execute(
+                "UPDATE import_quarantine SET line = ?2, id = ?3 WHERE rowid = ?1",
+                (rowid, redacted, id),
+            )?;
+        }
+    }
+    Ok(rows)
+}
+
+/// Whether the card IDs in the database are hashed
+pub fn is_hashed(conn: &Connection) -> bool {
+    conn.query_row("SELECT EXISTS (SELECT 1 FROM card_hash)", [], |row| {
+        row.get(0)
+    })
+    .unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_with, fingerprint, hash, is_hashed};
+    use crate::clock::SystemClock;
+    use crate::fixtures::Fixture;
+    use crate::models::{get_days, Person};
+
+    #[test]
+    fn existing_rows_are_rewritten_once() {
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-11 08:00", "2025-02-11 16:00"])
+            .beeps(1234567891, &["2025-02-11 09:00"])
+            .user("ola", &[1234567890])
+            .build();
+        // Quarantined before the id column existed
+        conn.execute(
+            "INSERT INTO import_quarantine (path, line_number, line, error)
+            VALUES ('20250211.csv', 3, '2025-02-11 08:00:00.123456;1234567890', 'Expected a comma')",
+            (),
+        )
+        .unwrap();
+        let key = b"hemmelig";
+        assert_ne!(hash(key, 1234567890), hash(b"annen", 1234567890));
+        // Wider than card numbers, but still a positive SQLite integer
+        assert!(hash(key, 1234567890) > u32::MAX.into());
+        assert!(hash(key, 1234567890) <= i64::MAX as u64);
+
+        check_with(&conn, Some(key)).unwrap();
+        assert!(is_hashed(&conn));
+        // A second start must not hash the hashes
+        check_with(&conn, Some(key)).unwrap();
+
+        let id = hash(key, 1234567890);
+        assert_eq!(get_days(&conn, &[id], None).unwrap()[0].beeps, 2);
+        assert_eq!(
+            Person::find(&conn, &SystemClock, "ola", None)
+                .unwrap()
+                .unwrap()
+                .id,
+            id
+        );
+        let (line, quarantined): (String, u64) = conn
+            .query_row("SELECT line, id FROM import_quarantine", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(quarantined, id);
+        assert_eq!(line, format!("2025-02-11 08:00:00.123456;{id}"));
+        let raw: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM logs WHERE id IN (1234567890, 1234567891)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, 0);
+    }
+
+    #[test]
+    fn other_keys_are_errors_naming_both_fingerprints() {
+        let conn = Fixture::new().build();
+        check_with(&conn, Some(b"hemmelig")).unwrap();
+
+        let err = check_with(&conn, Some(b"annen")).unwrap_err().to_string();
+        assert!(err.contains(&fingerprint(b"hemmelig")), "{err}");
+        assert!(err.contains(&fingerprint(b"annen")), "{err}");
+        let err = check_with(&conn, None).unwrap_err().to_string();
+        assert!(err.contains("configure card_hash in sal.json"), "{err}");
+    }
+}
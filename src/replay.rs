@@ -0,0 +1,244 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use ratatui::crossterm::event;
+use ratatui::DefaultTerminal;
+
+use crate::clock::{Clock, FixedClock};
+use crate::config::{BackupConfig, Config};
+use crate::migrate::create_tables;
+use crate::models::{open_db, DEFAULT_LOCATION};
+use crate::App;
+
+/// Longest pause between two beeps, so nights and holidays pass quickly
+const MAX_PAUSE: Duration = Duration::from_secs(2);
+
+/// A beep read from a log
+#[derive(Debug, PartialEq)]
+pub struct Beep {
+    pub timestamp: DateTime<Utc>,
+    pub card: u32,
+    pub location: String,
+}
+
+/// Beeps replayed so far, and how long registering them and loading the stats took
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub beeps: usize,
+    /// Beeps that could not be registered, like two of the same card at the same time
+    pub errors: usize,
+    pub total: Duration,
+    pub slowest: Duration,
+}
+
+impl ReplaySummary {
+    fn add(&mut self, took: Duration, failed: bool) {
+        self.beeps += 1;
+        self.errors += usize::from(failed);
+        self.total += took;
+        self.slowest = self.slowest.max(took);
+    }
+}
+
+impl fmt::Display for ReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let average = self
+            .total
+            .checked_div(self.beeps as u32)
+            .unwrap_or_default();
+        write!(
+            f,
+            "Replayed {} beeps, {} failed. Registering and loading stats took {average:?} on average and {:?} at most",
+            self.beeps, self.errors, self.slowest
+        )
+    }
+}
+
+/// Reads `timestamp,card ID[,location]` lines from a log file of the prototype, or from every
+/// file in a directory like the one written by `dump`, ordered by time
+pub fn read_beeps(path: &Path) -> io::Result<Vec<Beep>> {
+    let files = match path.is_dir() {
+        true => fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?,
+        false => vec![path.to_path_buf()],
+    };
+    let mut beeps = vec![];
+    for file in files.iter().sorted() {
+        for (i, line) in fs::read_to_string(file)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let beep = parse_line(line)
+                .map_err(|err| io::Error::other(format!("{}:{}: {err}", file.display(), i + 1)))?;
+            beeps.push(beep);
+        }
+    }
+    beeps.sort_by_key(|beep| beep.timestamp);
+    Ok(beeps)
+}
+
+fn parse_line(line: &str) -> Result<Beep, String> {
+    let mut parts = line.split(',').map(str::trim);
+    let (Some(timestamp), Some(card)) = (parts.next(), parts.next()) else {
+        return Err("Expected `timestamp,card ID`".to_string());
+    };
+    let timestamp = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|err| format!("Invalid timestamp: {err}"))?;
+    let card = card
+        .parse()
+        .map_err(|_| format!("Invalid card ID {card:?}"))?;
+    // Written by `dump` for beeps outside the default room
+    let location = parts.next().unwrap_or(DEFAULT_LOCATION).to_string();
+    Ok(Beep {
+        timestamp: timestamp.to_utc(),
+        card,
+        location,
+    })
+}
+
+/// Beeps the card in the room of `beep` at the time of `beep`, returning how long it took
+fn step(app: &mut App, clock: &FixedClock, beep: &Beep) -> Duration {
+    clock.set(beep.timestamp);
+    app.config.location.clone_from(&beep.location);
+    let started = Instant::now();
+    app.beep_user(beep.card);
+    started.elapsed()
+}
+
+/// Beeps every beep with the time between them divided by `speed`, until done or closed
+fn play(
+    app: &mut App,
+    clock: &FixedClock,
+    beeps: &[Beep],
+    speed: f64,
+    terminal: &mut DefaultTerminal,
+) -> io::Result<ReplaySummary> {
+    let mut summary = ReplaySummary::default();
+    for beep in beeps {
+        let pause = (beep.timestamp - clock.now()).to_std().unwrap_or_default();
+        let until = Instant::now() + pause.div_f64(speed).min(MAX_PAUSE);
+        loop {
+            terminal.draw(|frame| app.draw(frame))?;
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            if event::poll(left)? {
+                app.handle_events()?;
+            }
+            if app.exit {
+                return Ok(summary);
+            }
+        }
+        let took = step(app, clock, beep);
+        summary.add(took, app.db_error.is_some());
+    }
+    Ok(summary)
+}
+
+/// Plays back the beeps in `path` through the kiosk against a new database, `speed` times
+/// faster than they happened. The last beep stays on screen until the app is closed.
+pub fn replay(path: &Path, db: Option<&Path>, speed: f64) -> io::Result<()> {
+    let beeps = read_beeps(path)?;
+    let Some(first) = beeps.first() else {
+        return Err(io::Error::other(format!(
+            "Found no beeps in {}",
+            path.display()
+        )));
+    };
+    let conn = match db {
+        Some(db) if db.exists() => {
+            return Err(io::Error::other(format!(
+                "{} already exists, replay into a new database",
+                db.display()
+            )))
+        }
+        Some(db) => open_db(db),
+        None => open_db(Path::new(":memory:")),
+    };
+    create_tables(&conn)?;
+    // Nothing is sent anywhere or backed up
+    let config = Config {
+        heatmap: Config::try_load().map_err(io::Error::other)?.heatmap,
+        backup: BackupConfig {
+            enabled: false,
+            ..BackupConfig::default()
+        },
+        ..Config::default()
+    };
+    let clock = Rc::new(FixedClock::new(first.timestamp));
+    let mut app = App::new(conn, config, Box::new(clock.clone()));
+
+    let mut terminal = ratatui::init();
+    let result = play(&mut app, &clock, &beeps, speed, &mut terminal).and_then(|summary| {
+        if !app.exit {
+            app.run(&mut terminal)?;
+        }
+        Ok(summary)
+    });
+    ratatui::restore();
+    println!("{}", result?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::rc::Rc;
+
+    use super::{read_beeps, step, ReplaySummary};
+    use crate::clock::FixedClock;
+    use crate::config::Config;
+    use crate::fixtures::Fixture;
+    use crate::App;
+
+    #[test]
+    fn dumps_are_replayed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        // One file per day like `dump`, where the room is only written outside the default one
+        fs::write(
+            dir.path().join("20250211.csv"),
+            "2025-02-11T08:00:00+01:00,1234567890\n2025-02-11T09:00:00+01:00,1234567890,kjeller\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("20250210.csv"),
+            "2025-02-10T08:00:00+01:00,1234567890\n\n2025-02-10T08:00:00+01:00,1234567890\n",
+        )
+        .unwrap();
+        let beeps = read_beeps(dir.path()).unwrap();
+        assert_eq!(beeps.len(), 4);
+        assert_eq!(beeps[3].location, "kjeller");
+
+        let clock = Rc::new(FixedClock::new(beeps[0].timestamp));
+        let mut app = App::new(
+            Fixture::new().build(),
+            Config::default(),
+            Box::new(clock.clone()),
+        );
+        let mut summary = ReplaySummary::default();
+        for beep in &beeps {
+            let took = step(&mut app, &clock, beep);
+            summary.add(took, app.db_error.is_some());
+        }
+        // The second beep at the same time is rejected
+        assert_eq!((summary.beeps, summary.errors), (4, 1));
+        let user = app.current_user.unwrap();
+        assert_eq!(user.stats.latest.beeps, 2);
+        assert_eq!(app.config.location, "kjeller");
+
+        fs::write(dir.path().join("broken.csv"), "yesterday,1234567890\n").unwrap();
+        let err = read_beeps(dir.path()).unwrap_err().to_string();
+        assert!(
+            err.ends_with("broken.csv:1: Invalid timestamp: premature end of input"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use chrono::{Months, NaiveDate, Utc};
+use rusqlite::Connection;
+
+use crate::config::RetentionConfig;
+use crate::migrate::create_tables;
+use crate::models::{open_db, rollover_date};
+
+/// What `collapse` removed, or would remove
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneReport {
+    /// Days of one card that were collapsed
+    pub days: usize,
+    pub rows_deleted: usize,
+    pub first: Option<NaiveDate>,
+    pub last: Option<NaiveDate>,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => write!(
+                f,
+                "{} beeps on {} days from {first} to {last}",
+                self.rows_deleted, self.days
+            ),
+            _ => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Days of a card in a room with more beeps than the first and the last, before `?1`
+const COLLAPSIBLE: &str = "
+    SELECT id, date, location, COUNT(*) AS n
+    FROM logs
+    WHERE date < ?1
+    GROUP BY id, date, location
+    HAVING n > 2";
+
+/// The first day kept as raw beeps when keeping `months` months
+pub fn cutoff(today: NaiveDate, months: u32) -> NaiveDate {
+    today - Months::new(months)
+}
+
+/// What `collapse` would do with days before `before`
+pub fn report(conn: &Connection, before: NaiveDate) -> PruneReport {
+    conn.query_row(
+        &format!(
+            "SELECT COUNT(*), COALESCE(SUM(n - 2), 0), MIN(date), MAX(date) FROM ({COLLAPSIBLE})"
+        ),
+        [before],
+        |row| {
+            Ok(PruneReport {
+                days: row.get(0)?,
+                rows_deleted: row.get(1)?,
+                first: row.get(2)?,
+                last: row.get(3)?,
+            })
+        },
+    )
+    .unwrap()
+}
+
+/// Collapses the beeps of each card in each room on days before `before` into the first and
+/// the last. The last beep keeps the count of the ones deleted, so days, stats and heatmaps are unchanged.
+pub fn collapse(conn: &Connection, before: NaiveDate) -> rusqlite::Result<PruneReport> {
+    let tx = conn.unchecked_transaction()?;
+    let report = report(&tx, before);
+    tx.execute(
+        &format!(
+            "UPDATE logs SET beeps = (
+            SELECT SUM(other.beeps) FROM logs AS other
+            WHERE other.id = logs.id AND other.date = logs.date
+            AND other.location = logs.location
+            AND other.timestamp > (
+                SELECT MIN(first.timestamp) FROM logs AS first
+                WHERE first.id = logs.id AND first.date = logs.date
+                AND first.location = logs.location
+            )
+        )
+        WHERE (id, date, location) IN (SELECT id, date, location FROM ({COLLAPSIBLE}))
+        AND timestamp = (
+            SELECT MAX(last.timestamp) FROM logs AS last
+            WHERE last.id = logs.id AND last.date = logs.date
+            AND last.location = logs.location
+        )"
+        ),
+        [before],
+    )?;
+    tx.execute(
+        &format!(
+            "DELETE FROM logs
+        WHERE (id, date, location) IN (SELECT id, date, location FROM ({COLLAPSIBLE}))
+        AND timestamp > (
+            SELECT MIN(first.timestamp) FROM logs AS first
+            WHERE first.id = logs.id AND first.date = logs.date
+            AND first.location = logs.location
+        )
+        AND timestamp < (
+            SELECT MAX(last.timestamp) FROM logs AS last
+            WHERE last.id = logs.id AND last.date = logs.date
+            AND last.location = logs.location
+        )"
+        ),
+        [before],
+    )?;
+    tx.commit()?;
+    Ok(report)
+}
+
+/// Collapses beeps older than the configured number of months, printing what was collapsed
+pub fn prune(db: &Path, config: &RetentionConfig, dry_run: bool) -> io::Result<()> {
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    let before = cutoff(rollover_date(Utc::now()), config.months);
+    if dry_run {
+        println!("Would collapse {} before {before}", report(&conn, before));
+        return Ok(());
+    }
+    let report = collapse(&conn, before).map_err(io::Error::other)?;
+    println!("Collapsed {report} before {before}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{collapse, report, PruneReport};
+    use crate::fixtures::Fixture;
+    use crate::models::get_days;
+
+    #[test]
+    fn old_days_keep_their_first_and_last_beep() {
+        let conn = Fixture::new().build();
+        conn.execute_batch(
+            "INSERT INTO logs (id, timestamp, date) VALUES
+                (1, '2024-03-04 07:00:00+00:00', '2024-03-04'),
+                (1, '2024-03-04 10:00:00+00:00', '2024-03-04'),
+                (1, '2024-03-04 11:00:00+00:00', '2024-03-04'),
+                (1, '2024-03-04 15:00:00+00:00', '2024-03-04'),
+                (1, '2024-03-05 07:00:00+00:00', '2024-03-05'),
+                (1, '2024-03-05 15:00:00+00:00', '2024-03-05'),
+                (1, '2024-03-06 07:00:00+00:00', '2024-03-06'),
+                (1, '2024-03-06 15:00:00+00:00', '2024-03-06'),
+                (1, '2025-03-04 07:00:00+00:00', '2025-03-04'),
+                (1, '2025-03-04 10:00:00+00:00', '2025-03-04'),
+                (1, '2025-03-04 15:00:00+00:00', '2025-03-04');",
+        )
+        .unwrap();
+        // Beeps in another room that day only count towards that room
+        conn.execute_batch(
+            "INSERT INTO logs (id, timestamp, date, location) VALUES
+                (1, '2024-03-06 09:00:00+00:00', '2024-03-06', 'kjeller'),
+                (1, '2024-03-06 10:00:00+00:00', '2024-03-06', 'kjeller'),
+                (1, '2024-03-06 12:00:00+00:00', '2024-03-06', 'kjeller');",
+        )
+        .unwrap();
+        let before = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+        let days = |location| {
+            get_days(&conn, &[1], location)
+                .unwrap()
+                .iter()
+                .map(|day| (day.date, day.start, day.end, day.beeps))
+                .collect::<Vec<_>>()
+        };
+        let before_collapse = [None, Some("sal"), Some("kjeller")].map(days);
+
+        let expected = PruneReport {
+            days: 2,
+            rows_deleted: 3,
+            first: Some(NaiveDate::from_ymd_opt(2024, 3, 4).unwrap()),
+            last: Some(NaiveDate::from_ymd_opt(2024, 3, 6).unwrap()),
+        };
+        assert_eq!(report(&conn, before), expected);
+        assert_eq!(collapse(&conn, before).unwrap(), expected);
+        // Running again finds nothing more to do
+        assert_eq!(collapse(&conn, before).unwrap(), PruneReport::default());
+
+        let rows: usize = conn
+            .query_row("SELECT COUNT(*) FROM logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 11);
+        assert_eq!(
+            [None, Some("sal"), Some("kjeller")].map(days),
+            before_collapse
+        );
+    }
+}
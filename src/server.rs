@@ -0,0 +1,530 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use chrono::{NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use json::JsonValue;
+use rusqlite::Connection;
+use sha2::Sha256;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::dashboard::{self, leaderboard_start};
+use crate::github_map::Metric;
+use crate::metrics;
+use crate::migrate::create_tables;
+use crate::models::{
+    get_days, get_leaderboard, get_locations, get_occupancy, get_users, open_db, rollover_date,
+    Day, Person,
+};
+use crate::sync;
+
+/// Requests are handled in parallel, each worker with its own connection
+const WORKERS: usize = 4;
+
+/// Serves the dashboard, and users, days and stats as JSON, until killed
+pub fn serve(db: &Path, config: &Config) -> io::Result<()> {
+    // Kiosks syncing here need the tables even if no beep has been registered yet
+    create_tables(&open_db(db))?;
+    let server = Arc::new(Server::http(&config.server.addr).map_err(io::Error::other)?);
+    println!("Listening on http://{}", config.server.addr);
+    if config.server.token.is_none() {
+        println!("No token configured, registering beeps and syncing are disabled");
+    }
+
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let db = PathBuf::from(db);
+            let config = config.clone();
+            thread::spawn(move || {
+                let conn = open_db(&db);
+                for request in server.incoming_requests() {
+                    if let Err(err) = handle(&conn, &config, request) {
+                        eprintln!("Failed to respond: {err}");
+                    }
+                }
+            })
+        })
+        .collect_vec();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    Ok(())
+}
+
+fn handle(conn: &Connection, config: &Config, mut request: Request) -> io::Result<()> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.to_string());
+
+    let reply = route(
+        conn,
+        config,
+        request.method(),
+        request.url(),
+        authorization.as_deref(),
+        &body,
+    );
+
+    let content_type = Header::from_bytes("Content-Type", reply.content_type).unwrap();
+    let response = Response::from_string(reply.body)
+        .with_status_code(reply.status)
+        .with_header(content_type);
+    request.respond(response)
+}
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn json(status: u16, body: JsonValue) -> Self {
+        Self {
+            status,
+            content_type: "application/json; charset=utf-8",
+            body: json::stringify(body),
+        }
+    }
+
+    fn html(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json::object! { error: message })
+    }
+}
+
+fn route(
+    conn: &Connection,
+    config: &Config,
+    method: &Method,
+    url: &str,
+    authorization: Option<&str>,
+    body: &str,
+) -> Reply {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let query = parse_query(query);
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect_vec();
+    let segments = segments.iter().map(String::as_str).collect_vec();
+
+    let (from, to) = match (date_param(&query, "from"), date_param(&query, "to")) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return Reply::error(400, "Dates must be YYYY-MM-DD"),
+    };
+    // Stats, days, occupancy and leaderboards cover every room unless one is asked for
+    let location = query
+        .iter()
+        .find(|(key, _)| key == "location")
+        .map(|(_, location)| location.as_str());
+
+    match (method, segments.as_slice()) {
+        (Method::Get, []) => {
+            let metric = match query.iter().find(|(key, _)| key == "metric") {
+                Some((_, metric)) => match metric.parse::<Metric>() {
+                    Ok(metric) => metric,
+                    Err(err) => return Reply::error(400, &err),
+                },
+                None => config.heatmap.metric,
+            };
+            Reply::html(dashboard::render(conn, &config.heatmap, metric, location))
+        }
+        (Method::Get, ["api", "users"]) => {
+            let users = get_users(conn)
+                .into_iter()
+                .map(|(username, days, last_seen)| {
+                    json::object! {
+                        username: username,
+                        days: days,
+                        last_seen: last_seen.to_rfc3339(),
+                    }
+                })
+                .collect_vec();
+            Reply::json(200, users.into())
+        }
+        (Method::Get, ["api", "locations"]) => {
+            let locations = match get_locations(conn) {
+                Ok(locations) => locations,
+                Err(err) => return Reply::error(500, &err.to_string()),
+            };
+            let locations = locations
+                .into_iter()
+                .map(|(location, beeps)| json::object! { location: location, beeps: beeps })
+                .collect_vec();
+            Reply::json(200, locations.into())
+        }
+        (Method::Get, ["api", "users", username]) => {
+            match Person::find(conn, &SystemClock, username, location) {
+                Ok(Some(user)) => Reply::json(200, user_json(&user)),
+                Ok(None) => Reply::error(404, "No such user"),
+                Err(err) => Reply::error(500, &err.to_string()),
+            }
+        }
+        (Method::Get, ["api", "users", username, "days"]) => {
+            match Person::find(conn, &SystemClock, username, location) {
+                Ok(Some(user)) => {
+                    let days = match get_days(conn, &user.ids, location) {
+                        Ok(days) => days,
+                        Err(err) => return Reply::error(500, &err.to_string()),
+                    };
+                    let days = days
+                        .iter()
+                        .rev()
+                        .filter(|day| from.is_none_or(|from| day.date >= from))
+                        .filter(|day| to.is_none_or(|to| day.date <= to))
+                        .map(day_json)
+                        .collect_vec();
+                    Reply::json(200, days.into())
+                }
+                Ok(None) => Reply::error(404, "No such user"),
+                Err(err) => Reply::error(500, &err.to_string()),
+            }
+        }
+        (Method::Get, ["api", "occupancy"]) => {
+            let date = rollover_date(Utc::now());
+            let present = match get_occupancy(conn, date, location) {
+                Ok(present) => present,
+                Err(err) => return Reply::error(500, &err.to_string()),
+            };
+            Reply::json(
+                200,
+                json::object! {
+                    date: date.to_string(),
+                    location: location,
+                    count: present.len(),
+                    present: present,
+                },
+            )
+        }
+        (Method::Get, ["api", "leaderboard"]) => {
+            let from = from.unwrap_or_else(|| leaderboard_start(rollover_date(Utc::now())));
+            match get_leaderboard(conn, Some(from), to, location) {
+                Ok(leaderboard) => {
+                    let leaderboard = leaderboard
+                        .into_iter()
+                        .map(|(username, days, total)| {
+                            json::object! {
+                                username: username,
+                                days: days,
+                                duration_seconds: total.num_seconds(),
+                            }
+                        })
+                        .collect_vec();
+                    Reply::json(200, leaderboard.into())
+                }
+                Err(err) => Reply::error(500, &err.to_string()),
+            }
+        }
+        (Method::Post, ["api", "beeps"]) => {
+            if let Err(reply) = authorize(config, authorization) {
+                return reply;
+            }
+            let Ok(body) = json::parse(body) else {
+                return Reply::error(400, "Expected {\"id\": <card ID>}");
+            };
+            let Some(uid) = body["id"].as_u32() else {
+                return Reply::error(400, "Expected {\"id\": <card ID>}");
+            };
+            // Readers in other rooms can post their beeps here
+            let location = body["location"].as_str().unwrap_or(&config.location);
+            match Person::register(conn, &SystemClock, uid, location)
+                .and_then(|_| Person::load(conn, &SystemClock, uid))
+            {
+                Ok(user) => Reply::json(201, user_json(&user)),
+                Err(err) => Reply::error(500, &err.to_string()),
+            }
+        }
+        (Method::Post, ["api", "sync"]) => {
+            if let Err(reply) = authorize(config, authorization) {
+                return reply;
+            }
+            let Ok(body) = json::parse(body) else {
+                return Reply::error(400, "Expected JSON");
+            };
+            match sync::receive(conn, &body) {
+                Ok(reply) => Reply::json(200, reply),
+                Err(err) => Reply::error(400, &err),
+            }
+        }
+        (Method::Get, ["metrics"]) => match metrics::render(conn) {
+            Ok(body) => Reply {
+                status: 200,
+                content_type: metrics::CONTENT_TYPE,
+                body,
+            },
+            Err(err) => Reply::error(500, &err.to_string()),
+        },
+        _ => Reply::error(404, "Not found"),
+    }
+}
+
+/// Writing requires the configured token, and is disabled without one
+fn authorize(config: &Config, authorization: Option<&str>) -> Result<(), Reply> {
+    let Some(token) = &config.server.token else {
+        return Err(Reply::error(
+            403,
+            "Writing is disabled, no token is configured",
+        ));
+    };
+    let given = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    // Compared even without a token, so every refusal looks the same
+    let matches = token_matches(token, given.unwrap_or_default());
+    if given.is_none() || !matches {
+        return Err(Reply::error(401, "Missing or wrong token"));
+    }
+    Ok(())
+}
+
+/// Compares MACs of the tokens in constant time, so neither the token nor its length can be
+/// found by timing the replies
+fn token_matches(token: &str, given: &str) -> bool {
+    let mac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"sal token").unwrap();
+        mac.update(value.as_bytes());
+        mac
+    };
+    mac(given)
+        .verify_slice(&mac(token).finalize().into_bytes())
+        .is_ok()
+}
+
+fn user_json(user: &Person) -> JsonValue {
+    let stats = &user.stats;
+    json::object! {
+        username: user.username.as_str(),
+        streak: stats.streak,
+        streak_at_risk: stats.streak_at_risk,
+        last_week_count: stats.last_week_count,
+        last_month_count: stats.last_month_count,
+        // Left out until the user has beeped today
+        today: (stats.latest.date == stats.as_of).then(|| day_json(&stats.latest)),
+        last_visit: day_json(&stats.latest),
+        longest_day: day_json(&stats.longest_day),
+        earliest_arrival: day_json(&stats.earliest_arrival),
+        latest_departure: day_json(&stats.latest_departure),
+    }
+}
+
+/// Same fields as the JSON export
+fn day_json(day: &Day) -> JsonValue {
+    json::object! {
+        date: day.date.to_string(),
+        first: day.start.to_rfc3339(),
+        last: day.end.to_rfc3339(),
+        duration_seconds: day.span().num_seconds(),
+        beeps: day.beeps,
+        coffee: day.coffee,
+    }
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect()
+}
+
+fn date_param(query: &[(String, String)], key: &str) -> Result<Option<NaiveDate>, ()> {
+    match query.iter().find(|(k, _)| k == key) {
+        Some((_, value)) => value.parse().map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as space. Invalid escapes are kept as they are.
+fn percent_decode(text: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(if byte == b'+' { b' ' } else { byte });
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use tiny_http::Method;
+
+    use super::{percent_decode, route};
+    use crate::clock::SystemClock;
+    use crate::config::Config;
+    use crate::fixtures::{today, Fixture};
+    use crate::models::{set_visibility, Person};
+
+    #[test]
+    fn beeps_require_the_token() {
+        let conn = Fixture::new().build();
+        let body = r#"{"id": 1234567890}"#;
+
+        let mut config = Config::default();
+        let reply = route(&conn, &config, &Method::Post, "/api/beeps", None, body);
+        assert_eq!(reply.status, 403);
+        config.server.token = Some("secret".to_string());
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer secre"),
+            Some("secret"),
+        ] {
+            let reply = route(
+                &conn,
+                &config,
+                &Method::Post,
+                "/api/beeps",
+                authorization,
+                body,
+            );
+            assert_eq!(reply.status, 401);
+            assert_eq!(reply.body, r#"{"error":"Missing or wrong token"}"#);
+        }
+
+        let reply = route(
+            &conn,
+            &config,
+            &Method::Post,
+            "/api/beeps",
+            Some("Bearer secret"),
+            body,
+        );
+        assert_eq!(reply.status, 201);
+        let occupancy = route(&conn, &config, &Method::Get, "/api/occupancy", None, "");
+        let occupancy = json::parse(&occupancy.body).unwrap();
+        assert_eq!(occupancy["count"], 1);
+        assert_eq!(occupancy["present"][0], "1234567890");
+    }
+
+    #[test]
+    fn users_are_found_by_username() {
+        let conn = Fixture::new()
+            .beeps(1234567890, &[&today("08:00")])
+            .user("ola nordmann", &[1234567890])
+            .build();
+
+        let config = Config::default();
+        let get = |url| route(&conn, &config, &Method::Get, url, None, "");
+        let user = json::parse(&get("/api/users/ola%20nordmann").body).unwrap();
+        assert_eq!(user["username"], "ola nordmann");
+        assert_eq!(user["today"]["beeps"], 1);
+        assert_eq!(user["last_visit"], user["today"]);
+        let days = json::parse(&get("/api/users/ola%20nordmann/days").body).unwrap();
+        assert_eq!(days.len(), 1);
+        let leaderboard = json::parse(&get("/api/leaderboard").body).unwrap();
+        assert_eq!(leaderboard[0]["username"], "ola nordmann");
+
+        assert_eq!(get("/api/users/kari").status, 404);
+        assert_eq!(
+            get("/api/users/ola%20nordmann/days?from=yesterday").status,
+            400
+        );
+        assert_eq!(percent_decode("%C3%B8+%zz"), "ø %zz");
+    }
+
+    #[test]
+    fn old_visits_are_not_today() {
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-10 08:00"])
+            .user("ola", &[1234567890])
+            .build();
+        let reply = route(
+            &conn,
+            &Config::default(),
+            &Method::Get,
+            "/api/users/ola",
+            None,
+            "",
+        );
+        let user = json::parse(&reply.body).unwrap();
+        assert!(user["today"].is_null());
+        assert_eq!(user["last_visit"]["date"], "2025-02-10");
+        assert_eq!(user["last_week_count"], 0);
+    }
+
+    #[test]
+    fn days_span_rooms() {
+        // In and out of one room, then into another
+        let conn = Fixture::new()
+            .beeps_in("sal", 1234567890, &[&today("08:00"), &today("09:00")])
+            .beeps_in("kjeller", 1234567890, &[&today("10:00")])
+            .build();
+
+        let config = Config::default();
+        let get = |url| json::parse(&route(&conn, &config, &Method::Get, url, None, "").body);
+        let days = get("/api/users/1234567890/days").unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0]["beeps"], 3);
+        let days = get("/api/users/1234567890/days?location=kjeller").unwrap();
+        assert_eq!(days[0]["beeps"], 1);
+
+        assert_eq!(get("/api/occupancy?location=kjeller").unwrap()["count"], 1);
+        assert_eq!(get("/api/occupancy?location=sal").unwrap()["count"], 0);
+        let locations = get("/api/locations").unwrap();
+        assert_eq!(locations[0]["location"], "kjeller");
+        assert_eq!(locations[1]["beeps"], 2);
+    }
+
+    #[test]
+    fn hidden_users_are_left_out() {
+        let conn = Fixture::new()
+            .beeps(1234567890, &[&today("08:00")])
+            .beeps(1234567891, &[&today("08:00")])
+            .beeps(1234567892, &[&today("08:00")])
+            .user("ola", &[1234567890, 1234567891])
+            .build();
+        // Hiding one card hides every card of the user
+        set_visibility(&conn, 1234567891, true, chrono::Utc::now()).unwrap();
+        assert!(
+            Person::load(&conn, &SystemClock, 1234567890)
+                .unwrap()
+                .hidden
+        );
+
+        let config = Config::default();
+        let get = |url| route(&conn, &config, &Method::Get, url, None, "");
+        let parse = |url| json::parse(&get(url).body).unwrap();
+        assert_eq!(get("/api/users/ola").status, 404);
+        assert_eq!(parse("/api/users").len(), 1);
+        let leaderboard = parse("/api/leaderboard");
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0]["username"], "1234567892");
+        assert_eq!(parse("/api/occupancy")["present"][0], "1234567892");
+
+        let ola = Person::load(&conn, &SystemClock, 1234567890).unwrap();
+        ola.set_hidden(&conn, &SystemClock, false);
+        assert_eq!(get("/api/users/ola").status, 200);
+        assert_eq!(parse("/api/leaderboard").len(), 2);
+    }
+}
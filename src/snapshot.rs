@@ -0,0 +1,343 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::Utc;
+use itertools::Itertools;
+use json::JsonValue;
+use rusqlite::{
+    types::{Value, ValueRef},
+    Connection,
+};
+use sha2::{Digest, Sha256};
+
+use crate::models::open_db;
+
+const FORMAT: &str = "sal-snapshot";
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+
+/// Writes every table of the database at `db` into `dir`, one JSON array per row,
+/// along with a manifest holding the schema and a checksum of each table.
+pub fn snapshot(db: &Path, dir: &Path) -> io::Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", dir.display()),
+        ));
+    }
+    fs::create_dir_all(dir)?;
+
+    let mut conn = open_db(db);
+    // Read everything in one transaction, so the snapshot is consistent even if sal is running
+    let tx = conn.transaction().unwrap();
+
+    let schema = tx
+        .prepare(
+            "SELECT sql FROM sqlite_master
+            WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+            ORDER BY rowid",
+        )
+        .unwrap()
+        .query_map([], |row| row.get::<_, String>(0))
+        .unwrap()
+        .map(|sql| sql.unwrap())
+        .collect_vec();
+    let user_version: u32 = tx
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+
+    let mut tables = vec![];
+    for name in table_names(&tx) {
+        let (columns, contents, rows) = dump_table(&tx, &name);
+        let file = format!("{name}.jsonl");
+        fs::write(dir.join(&file), &contents)?;
+        tables.push(json::object! {
+            name: name.as_str(),
+            file: file,
+            columns: columns,
+            rows: rows,
+            sha256: sha256(contents.as_bytes()),
+        });
+    }
+
+    let manifest = json::object! {
+        format: FORMAT,
+        version: VERSION,
+        created: Utc::now().to_rfc3339(),
+        user_version: user_version,
+        schema: schema,
+        tables: tables,
+    };
+    fs::write(dir.join(MANIFEST), json::stringify_pretty(manifest, 2))?;
+    println!("Wrote snapshot to {}", dir.display());
+
+    Ok(())
+}
+
+/// Recreates the database at `db` from a snapshot, after checking that it is complete and unmodified
+pub fn restore(dir: &Path, db: &Path, force: bool) -> io::Result<()> {
+    let manifest = fs::read_to_string(dir.join(MANIFEST))?;
+    let manifest = json::parse(&manifest).map_err(invalid)?;
+    if manifest["format"] != FORMAT {
+        return Err(invalid("Not a sal snapshot"));
+    }
+    if manifest["version"].as_u32() != Some(VERSION) {
+        return Err(invalid(format!(
+            "Unsupported snapshot version {}",
+            manifest["version"]
+        )));
+    }
+
+    // Verify every file before touching the database
+    let mut tables = vec![];
+    for table in manifest["tables"].members() {
+        let name = table["name"]
+            .as_str()
+            .ok_or_else(|| invalid("Missing name"))?;
+        let file = table["file"]
+            .as_str()
+            .ok_or_else(|| invalid("Missing file"))?;
+        let contents = fs::read_to_string(dir.join(file))?;
+        if table["sha256"] != sha256(contents.as_bytes()) {
+            return Err(invalid(format!("Checksum mismatch for {file}")));
+        }
+        let rows = contents
+            .lines()
+            .map(|line| json::parse(line).map_err(invalid))
+            .collect::<io::Result<Vec<_>>>()?;
+        if table["rows"].as_usize() != Some(rows.len()) {
+            return Err(invalid(format!("Wrong number of rows in {file}")));
+        }
+        let columns = table["columns"]
+            .members()
+            .map(|column| column.to_string())
+            .collect_vec();
+        tables.push((name, columns, rows));
+    }
+
+    if db.exists() && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists. Use --force to replace it", db.display()),
+        ));
+    }
+
+    // Build the database next to the target, so a failed restore leaves it untouched
+    let restoring = db.with_extension("restoring");
+    if restoring.exists() {
+        fs::remove_file(&restoring)?;
+    }
+    let mut conn = open_db(&restoring);
+    let tx = conn.transaction().unwrap();
+    for sql in manifest["schema"].members() {
+        tx.execute(&sql.to_string(), []).unwrap();
+    }
+    for (name, columns, rows) in &tables {
+        let column_list = std::iter::once("rowid".to_string())
+            .chain(columns.iter().map(|column| format!("\"{column}\"")))
+            .join(", ");
+        let placeholders = (1..=columns.len() + 1).map(|i| format!("?{i}")).join(", ");
+        let mut stmt = tx
+            .prepare(&format!(
+                "INSERT INTO \"{name}\" ({column_list}) VALUES ({placeholders})"
+            ))
+            .unwrap();
+        for row in rows {
+            let values = row
+                .members()
+                .map(json_to_value)
+                .collect::<io::Result<Vec<_>>>()?;
+            stmt.execute(rusqlite::params_from_iter(values)).unwrap();
+        }
+    }
+    let user_version = manifest["user_version"].as_u32().unwrap_or(0);
+    tx.pragma_update(None, "user_version", user_version)
+        .unwrap();
+    tx.commit().unwrap();
+
+    // The restored tables must serialize to exactly what was snapshotted
+    for table in manifest["tables"].members() {
+        let (_, contents, _) = dump_table(&conn, &table["name"].to_string());
+        if table["sha256"] != sha256(contents.as_bytes()) {
+            drop(conn);
+            fs::remove_file(&restoring)?;
+            return Err(invalid(format!(
+                "Restored table {} differs from the snapshot",
+                table["name"]
+            )));
+        }
+    }
+    drop(conn);
+
+    // Leftovers from the replaced database would be applied to the restored one
+    for suffix in ["-wal", "-shm"] {
+        let mut path = db.as_os_str().to_owned();
+        path.push(suffix);
+        if Path::new(&path).exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    fs::rename(&restoring, db)?;
+    println!("Restored {} from {}", db.display(), dir.display());
+
+    Ok(())
+}
+
+fn table_names(conn: &Connection) -> Vec<String> {
+    conn.prepare(
+        "SELECT name FROM sqlite_master
+        WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+        ORDER BY name",
+    )
+    .unwrap()
+    .query_map([], |row| row.get(0))
+    .unwrap()
+    .map(|name| name.unwrap())
+    .collect()
+}
+
+/// Column names, rows as JSON lines with the rowid first, and number of rows
+fn dump_table(conn: &Connection, table: &str) -> (Vec<String>, String, usize) {
+    let mut stmt = conn
+        .prepare(&format!("SELECT rowid, * FROM \"{table}\" ORDER BY rowid"))
+        .unwrap();
+    let columns = stmt
+        .column_names()
+        .into_iter()
+        .skip(1)
+        .map(str::to_string)
+        .collect_vec();
+    let n_columns = stmt.column_count();
+
+    let mut contents = String::new();
+    let mut rows = stmt.query([]).unwrap();
+    let mut n_rows = 0;
+    while let Some(row) = rows.next().unwrap() {
+        let values = (0..n_columns)
+            .map(|i| value_to_json(row.get_ref(i).unwrap()))
+            .collect_vec();
+        contents.push_str(&json::stringify(values));
+        contents.push('\n');
+        n_rows += 1;
+    }
+    (columns, contents, n_rows)
+}
+
+/// Integers, text and null map directly to JSON.
+/// Reals and blobs are wrapped in objects so they are restored with the same type.
+pub fn value_to_json(value: ValueRef) -> JsonValue {
+    match value {
+        ValueRef::Null => JsonValue::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => json::object! { real: f.to_string() },
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned().into(),
+        ValueRef::Blob(blob) => json::object! {
+            blob: blob.iter().map(|b| format!("{b:02x}")).collect::<String>()
+        },
+    }
+}
+
+fn json_to_value(value: &JsonValue) -> io::Result<Value> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    if let Some(text) = value.as_str() {
+        return Ok(Value::Text(text.to_string()));
+    }
+    if let Some(i) = value.as_i64() {
+        return Ok(Value::Integer(i));
+    }
+    if let Some(real) = value["real"].as_str() {
+        return real.parse().map(Value::Real).map_err(invalid);
+    }
+    if let Some(hex) = value["blob"].as_str() {
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(invalid))
+            .collect::<io::Result<Vec<_>>>()
+            .map(Value::Blob);
+    }
+    Err(invalid(format!("Unexpected value {value}")))
+}
+
+fn sha256(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{dump_table, restore, snapshot, table_names};
+    use crate::migrate::create_tables;
+    use crate::models::{open_db, rollover_date};
+
+    #[test]
+    fn restore_reproduces_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("sal.db");
+        let conn = open_db(&db);
+        create_tables(&conn).unwrap();
+        let timestamp = Utc.with_ymd_and_hms(2025, 2, 4, 3, 0, 0).unwrap();
+        conn.execute(
+            "INSERT INTO logs (id, timestamp, date) VALUES (?1, ?2, ?3)",
+            (1234567890, timestamp, rollover_date(timestamp)),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO coffee (id, timestamp, date) VALUES (?1, ?2, ?3)",
+            (1234567890, timestamp, rollover_date(timestamp)),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO people (id, username) VALUES (1234567890, 'ola'), (1234567891, 'ola')",
+            (),
+        )
+        .unwrap();
+        conn.execute("CREATE TABLE extra (value REAL, data BLOB)", ())
+            .unwrap();
+        conn.execute("INSERT INTO extra VALUES (1.0, x'00ff'), (NULL, NULL)", ())
+            .unwrap();
+        conn.pragma_update(None, "user_version", 3).unwrap();
+
+        let snapshot_dir = dir.path().join("snapshot");
+        snapshot(&db, &snapshot_dir).unwrap();
+        let restored = dir.path().join("restored.db");
+        restore(&snapshot_dir, &restored, false).unwrap();
+
+        let restored = open_db(&restored);
+        assert_eq!(table_names(&conn), table_names(&restored));
+        for table in table_names(&conn) {
+            assert_eq!(dump_table(&conn, &table), dump_table(&restored, &table));
+        }
+        let user_version: u32 = restored
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(user_version, 3);
+    }
+
+    #[test]
+    fn modified_snapshots_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("sal.db");
+        let conn = open_db(&db);
+        create_tables(&conn).unwrap();
+        conn.execute("INSERT INTO people (id, username) VALUES (1, 'ola')", ())
+            .unwrap();
+
+        let snapshot_dir = dir.path().join("snapshot");
+        snapshot(&db, &snapshot_dir).unwrap();
+        fs::write(snapshot_dir.join("people.jsonl"), "[1,1,\"kari\"]\n").unwrap();
+
+        let restored = dir.path().join("restored.db");
+        assert!(restore(&snapshot_dir, &restored, false).is_err());
+        assert!(!restored.exists());
+    }
+}
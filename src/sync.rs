@@ -0,0 +1,513 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use json::JsonValue;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::config::SyncConfig;
+use crate::migrate::create_tables;
+use crate::models::{open_db, rollover_date, set_visibility, DEFAULT_LOCATION};
+use crate::pseudonym::stored_fingerprint;
+
+/// Beeps sent per request, so a kiosk that has been offline for long catches up in steps
+const BATCH: usize = 1000;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub logs_sent: usize,
+    /// Beeps the server did not already have
+    pub logs_added: usize,
+    pub users_sent: usize,
+    /// Usernames here replaced by ones set more recently on another kiosk
+    pub conflicts: usize,
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} beeps sent, {} new on the server, {} usernames sent, {} replaced by newer ones from other kiosks",
+            self.logs_sent, self.logs_added, self.users_sent, self.conflicts
+        )
+    }
+}
+
+/// A username and when it was set. Usernames from before this was tracked have no time,
+/// and lose to any change.
+#[derive(Debug, PartialEq)]
+struct Username {
+    id: u64,
+    username: String,
+    changed: Option<DateTime<Utc>>,
+}
+
+impl Username {
+    fn to_json(&self) -> JsonValue {
+        json::object! {
+            id: self.id,
+            username: self.username.as_str(),
+            changed: self.changed.map(|changed| changed.to_rfc3339()),
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let (Some(id), Some(username)) = (value["id"].as_u64(), value["username"].as_str()) else {
+            return Err("Users need an id and a username".to_string());
+        };
+        let changed = match value["changed"].as_str() {
+            Some(changed) => Some(parse_time(changed)?),
+            None => None,
+        };
+        Ok(Self {
+            id,
+            username: username.to_string(),
+            changed,
+        })
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.to_utc())
+        .map_err(|_| format!("Invalid timestamp {time:?}"))
+}
+
+/// Every hidden or shown profile. They are few, so all are sent on every sync.
+fn visibility(conn: &Connection) -> rusqlite::Result<Vec<JsonValue>> {
+    conn.prepare_cached("SELECT id, hidden, changed FROM visibility")?
+        .query_map([], |row| {
+            let changed: DateTime<Utc> = row.get(2)?;
+            Ok(json::object! {
+                id: row.get::<_, u64>(0)?,
+                hidden: row.get::<_, bool>(1)?,
+                changed: changed.to_rfc3339(),
+            })
+        })?
+        .collect()
+}
+
+/// Stores the visibility of profiles changed later elsewhere
+fn apply_visibility(conn: &Connection, profiles: &JsonValue) -> Result<(), String> {
+    for profile in profiles.members() {
+        let (Some(id), Some(hidden), Some(changed)) = (
+            profile["id"].as_u64(),
+            profile["hidden"].as_bool(),
+            profile["changed"].as_str(),
+        ) else {
+            return Err("Profiles need an id, hidden and changed".to_string());
+        };
+        set_visibility(conn, id, hidden, parse_time(changed)?).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Uploads beeps and usernames not yet sent to the central server.
+/// The outcome is kept in `sync_state`, so errors from the background worker can be found.
+pub fn sync(conn: &Connection, config: &SyncConfig) -> Result<SyncSummary, String> {
+    let result = upload(conn, config);
+    let recorded = conn.execute(
+        "INSERT INTO sync_state (server, last_attempt, last_error) VALUES (?1, ?2, ?3)
+        ON CONFLICT (server) DO UPDATE
+        SET last_attempt = excluded.last_attempt, last_error = excluded.last_error",
+        (&config.server, Utc::now(), result.as_ref().err()),
+    );
+    // Why the upload failed says more than failing to record it
+    let summary = result?;
+    recorded.map_err(|err| format!("Could not record the sync: {err}"))?;
+    Ok(summary)
+}
+
+fn upload(conn: &Connection, config: &SyncConfig) -> Result<SyncSummary, String> {
+    let (mut logs_rowid, mut users_changed): (i64, Option<DateTime<Utc>>) = conn
+        .query_row(
+            "SELECT logs_rowid, users_changed FROM sync_state WHERE server = ?1",
+            [&config.server],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|err| err.to_string())?
+        .unwrap_or((0, None));
+
+    // Usernames are few, so all changes go with the first batch. The first request is sent
+    // even when there is nothing new, to get usernames changed on other kiosks.
+    let mut users: Vec<Username> = conn
+        .prepare_cached("SELECT id, username, changed FROM people WHERE ?1 IS NULL OR changed > ?1")
+        .and_then(|mut stmt| {
+            stmt.query_map([users_changed], |row| {
+                Ok(Username {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    changed: row.get(2)?,
+                })
+            })?
+            .collect()
+        })
+        .map_err(|err| err.to_string())?;
+    let mut profiles = visibility(conn).map_err(|err| err.to_string())?;
+    // Lets the server refuse card IDs hashed with another key than its own
+    let card_hash = stored_fingerprint(conn).map_err(|err| err.to_string())?;
+
+    let mut summary = SyncSummary::default();
+    loop {
+        // Rows are only ever appended, so the rowid tells which beeps are new
+        let logs: Vec<(i64, u64, String, String, u32)> = conn
+            .prepare_cached(
+                "SELECT rowid, id, timestamp, location, beeps FROM logs
+                WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map((logs_rowid, BATCH), |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                })?
+                .collect()
+            })
+            .map_err(|err| err.to_string())?;
+
+        let body = json::object! {
+            logs: logs
+                .iter()
+                .map(|(_, id, timestamp, location, beeps)| {
+                    json::object! {
+                        id: *id,
+                        timestamp: timestamp.as_str(),
+                        location: location.as_str(),
+                        beeps: *beeps,
+                    }
+                })
+                .collect_vec(),
+            users: users.iter().map(Username::to_json).collect_vec(),
+            visibility: profiles.clone(),
+            card_hash: card_hash.clone(),
+        };
+        let reply = post(config, &json::stringify(body))?;
+
+        let tx = conn
+            .unchecked_transaction()
+            .map_err(|err| err.to_string())?;
+        apply_visibility(&tx, &reply["visibility"])?;
+        for user in reply["users"].members() {
+            let user = Username::from_json(user)?;
+            let current: Option<(String, Option<DateTime<Utc>>)> = tx
+                .query_row(
+                    "SELECT username, changed FROM people WHERE id = ?1",
+                    [user.id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .map_err(|err| err.to_string())?;
+            match current {
+                Some((username, _)) if username == user.username => continue,
+                // Changed here while the upload was underway, so the server gets it next time
+                Some((_, changed)) if changed > user.changed => continue,
+                Some(_) => summary.conflicts += 1,
+                None => (),
+            }
+            tx.execute(
+                "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3)
+                ON CONFLICT (id) DO UPDATE
+                SET username = excluded.username, changed = excluded.changed",
+                (user.id, &user.username, user.changed),
+            )
+            .map_err(|err| err.to_string())?;
+        }
+        if let Some((rowid, ..)) = logs.last() {
+            logs_rowid = *rowid;
+        }
+        users_changed = users
+            .iter()
+            .filter_map(|user| user.changed)
+            .chain(users_changed)
+            .max();
+        tx.execute(
+            "INSERT INTO sync_state (server, logs_rowid, users_changed) VALUES (?1, ?2, ?3)
+            ON CONFLICT (server) DO UPDATE
+            SET logs_rowid = excluded.logs_rowid, users_changed = excluded.users_changed",
+            (&config.server, logs_rowid, users_changed),
+        )
+        .map_err(|err| err.to_string())?;
+        tx.commit().map_err(|err| err.to_string())?;
+
+        summary.logs_sent += logs.len();
+        summary.logs_added += reply["logs_added"].as_usize().unwrap_or(0);
+        summary.users_sent += users.len();
+        users.clear();
+        profiles.clear();
+        if logs.len() < BATCH {
+            break;
+        }
+    }
+    Ok(summary)
+}
+
+fn post(config: &SyncConfig, body: &str) -> Result<JsonValue, String> {
+    let mut request = ureq::post(&format!("{}/api/sync", config.server))
+        .timeout(TIMEOUT)
+        .set("Content-Type", "application/json");
+    if let Some(token) = &config.token {
+        request = request.set("Authorization", &format!("Bearer {token}"));
+    }
+    let reply = match request.send_string(body) {
+        Ok(reply) => reply.into_string().map_err(|err| err.to_string())?,
+        // The server explains what was wrong in the body
+        Err(ureq::Error::Status(status, reply)) => {
+            let reply =
+                json::parse(&reply.into_string().unwrap_or_default()).unwrap_or(JsonValue::Null);
+            let error = reply["error"].as_str().unwrap_or("no explanation");
+            return Err(format!("The server answered {status}: {error}"));
+        }
+        Err(err) => return Err(err.to_string()),
+    };
+    json::parse(&reply).map_err(|err| err.to_string())
+}
+
+/// Stores an upload from a kiosk, on the central server. Beeps already stored are skipped,
+/// so uploading twice is harmless. A username is only replaced by one set later.
+/// Replies with every username and hidden profile, so kiosks learn about changes made on
+/// other kiosks.
+pub fn receive(conn: &Connection, body: &JsonValue) -> Result<JsonValue, String> {
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(|err| err.to_string())?;
+    // The same card has different IDs under different keys, and would count as two people
+    let card_hash = stored_fingerprint(&tx).map_err(|err| err.to_string())?;
+    if body["card_hash"].as_str() != card_hash.as_deref() {
+        return Err(
+            "The kiosk and the server hash card IDs differently, configure the same card_hash key on both"
+                .to_string(),
+        );
+    }
+
+    let mut logs_added = 0;
+    for log in body["logs"].members() {
+        let (Some(id), Some(timestamp)) = (log["id"].as_u64(), log["timestamp"].as_str()) else {
+            return Err("Logs need an id and a timestamp".to_string());
+        };
+        let date = rollover_date(parse_time(timestamp)?);
+        let location = log["location"].as_str().unwrap_or(DEFAULT_LOCATION);
+        let beeps = log["beeps"].as_u32().unwrap_or(1);
+        // The timestamp is stored exactly as on the kiosk, so a second upload hits the primary key
+        logs_added += tx
+            .prepare_cached(
+                "INSERT OR IGNORE INTO logs (id, timestamp, date, location, beeps)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .and_then(|mut stmt| stmt.execute((id, timestamp, date, location, beeps)))
+            .map_err(|err| err.to_string())?;
+    }
+
+    let mut users_updated = 0;
+    for user in body["users"].members() {
+        let user = Username::from_json(user)?;
+        let current: Option<(String, Option<DateTime<Utc>>)> = tx
+            .query_row(
+                "SELECT username, changed FROM people WHERE id = ?1",
+                [user.id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|err| err.to_string())?;
+        match current {
+            Some((username, _)) if username == user.username => (),
+            // Ties go to the server, so every kiosk ends up with the same username
+            Some((_, changed)) if changed >= user.changed => (),
+            _ => {
+                tx.execute(
+                    "INSERT INTO people (id, username, changed) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (id) DO UPDATE
+                    SET username = excluded.username, changed = excluded.changed",
+                    (user.id, &user.username, user.changed),
+                )
+                .map_err(|err| err.to_string())?;
+                users_updated += 1;
+            }
+        }
+    }
+
+    apply_visibility(&tx, &body["visibility"])?;
+
+    let users: Vec<JsonValue> = tx
+        .prepare("SELECT id, username, changed FROM people")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(Username {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    changed: row.get(2)?,
+                }
+                .to_json())
+            })?
+            .collect()
+        })
+        .map_err(|err| err.to_string())?;
+    let profiles = visibility(&tx).map_err(|err| err.to_string())?;
+    tx.commit().map_err(|err| err.to_string())?;
+    Ok(json::object! {
+        logs_added: logs_added,
+        users_updated: users_updated,
+        users: users,
+        visibility: profiles,
+    })
+}
+
+/// Uploads from a background thread with its own connection, every `interval_seconds`
+pub fn spawn_worker(db: PathBuf, config: SyncConfig) {
+    thread::spawn(move || {
+        let conn = open_db(&db);
+        loop {
+            // Failures are kept in `sync_state` and retried on the next round
+            let _ = sync(&conn, &config);
+            thread::sleep(Duration::from_secs(config.interval_seconds));
+        }
+    });
+}
+
+/// Uploads once and prints what was sent
+pub fn sync_now(db: &Path, config: &SyncConfig) -> io::Result<()> {
+    let conn = open_db(db);
+    create_tables(&conn)?;
+    let summary = sync(&conn, config).map_err(io::Error::other)?;
+    println!("Synced with {}: {summary}", config.server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    use super::{receive, sync};
+    use crate::clock::SystemClock;
+    use crate::config::SyncConfig;
+    use crate::migrate::create_tables;
+    use crate::models::{open_db, Person};
+
+    /// A central server answering `n` uploads
+    fn central(db: std::path::PathBuf, n: usize) -> SyncConfig {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || {
+            let conn = open_db(&db);
+            for mut request in server.incoming_requests().take(n) {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let response = match receive(&conn, &json::parse(&body).unwrap()) {
+                    Ok(reply) => Response::from_string(json::stringify(reply)),
+                    Err(err) => {
+                        Response::from_string(json::stringify(json::object! { error: err }))
+                            .with_status_code(400)
+                    }
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        SyncConfig {
+            server: url,
+            token: None,
+            interval_seconds: 60,
+        }
+    }
+
+    fn username(conn: &rusqlite::Connection, uid: u64) -> String {
+        conn.query_row("SELECT username FROM people WHERE id = ?1", [uid], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn kiosks_upload_once_and_agree_on_usernames() {
+        let dir = tempfile::tempdir().unwrap();
+        let kiosks = ["sal", "kjeller"].map(|location| {
+            let conn = open_db(&dir.path().join(format!("{location}.db")));
+            create_tables(&conn).unwrap();
+            Person::register(&conn, &SystemClock, 1234567890, location).unwrap();
+            conn
+        });
+        let central_db = dir.path().join("central.db");
+        create_tables(&open_db(&central_db)).unwrap();
+        let config = central(central_db.clone(), 4);
+
+        // Both kiosks name the same card, the last one to do so wins
+        Person::load(&kiosks[0], &SystemClock, 1234567890)
+            .unwrap()
+            .set_username(&kiosks[0], &SystemClock, "ola");
+        Person::load(&kiosks[1], &SystemClock, 1234567890)
+            .unwrap()
+            .set_username(&kiosks[1], &SystemClock, "ola nordmann");
+
+        let summary = sync(&kiosks[0], &config).unwrap();
+        assert_eq!((summary.logs_added, summary.conflicts), (1, 0));
+        let summary = sync(&kiosks[1], &config).unwrap();
+        assert_eq!((summary.logs_added, summary.users_sent), (1, 1));
+
+        // Nothing new is sent again
+        let summary = sync(&kiosks[1], &config).unwrap();
+        assert_eq!((summary.logs_sent, summary.users_sent), (0, 0));
+        // The first kiosk's username lost, and it takes the newer one
+        let summary = sync(&kiosks[0], &config).unwrap();
+        assert_eq!((summary.logs_sent, summary.conflicts), (0, 1));
+        assert_eq!(username(&kiosks[0], 1234567890), "ola nordmann");
+
+        let central = open_db(&central_db);
+        assert_eq!(username(&central, 1234567890), "ola nordmann");
+        let beeps: usize = central
+            .query_row("SELECT COUNT(*) FROM logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(beeps, 2);
+    }
+
+    #[test]
+    fn kiosks_hashing_with_another_key_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let kiosk = open_db(&dir.path().join("sal.db"));
+        create_tables(&kiosk).unwrap();
+        Person::register(&kiosk, &SystemClock, 1234567890, "sal").unwrap();
+        kiosk
+            .execute("INSERT INTO card_hash (fingerprint) VALUES ('abc')", ())
+            .unwrap();
+        let central_db = dir.path().join("central.db");
+        create_tables(&open_db(&central_db)).unwrap();
+        let config = central(central_db.clone(), 1);
+
+        let err = sync(&kiosk, &config).unwrap_err();
+        assert!(err.contains("hash card IDs differently"), "{err}");
+        let beeps: usize = open_db(&central_db)
+            .query_row("SELECT COUNT(*) FROM logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(beeps, 0);
+    }
+
+    #[test]
+    fn database_errors_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let kiosk = open_db(&dir.path().join("sal.db"));
+        create_tables(&kiosk).unwrap();
+        kiosk.execute("DROP TABLE visibility", ()).unwrap();
+        let config = SyncConfig::new("http://127.0.0.1:1");
+
+        let err = sync(&kiosk, &config).unwrap_err();
+        assert!(err.contains("no such table: visibility"), "{err}");
+        let last_error: String = kiosk
+            .query_row("SELECT last_error FROM sync_state", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(last_error, err);
+
+        // Even when the outcome cannot be recorded
+        kiosk.execute("DROP TABLE sync_state", ()).unwrap();
+        assert!(sync(&kiosk, &config).is_err());
+    }
+}
@@ -6,7 +6,7 @@ use ratatui::{
 };
 use tui_textarea::{CursorMove, Input, Key};
 
-use crate::{models::Person, App};
+use crate::App;
 
 pub fn handle_username_input(input: Event, app: &mut App) {
     match input.into() {
@@ -24,8 +24,8 @@ pub fn handle_username_input(input: Event, app: &mut App) {
             if let Some(user) = &mut app.current_user {
                 let uid = user.id;
                 let username = &app.textarea.lines()[0];
-                user.set_username(username);
-                app.current_user = Some(Person::load(uid));
+                user.set_username(&app.db, app.clock.as_ref(), username);
+                app.load_user(uid);
                 clear_popup(app);
             }
         }
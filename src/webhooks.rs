@@ -0,0 +1,302 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use json::JsonValue;
+use rusqlite::Connection;
+use sha2::Sha256;
+
+use crate::clock::Clock;
+use crate::config::WebhookConfig;
+use crate::events::Event;
+use crate::metrics;
+use crate::models::{open_db, Person};
+
+/// Header holding the HMAC-SHA256 of the body, when the webhook has a secret
+pub const SIGNATURE_HEADER: &str = "X-Sal-Signature";
+
+/// Deliveries are given up after this many attempts, but kept in the queue for inspection
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Waiting time before the first retry, doubled for each failed attempt
+const FIRST_RETRY: TimeDelta = TimeDelta::seconds(30);
+const MAX_RETRY: TimeDelta = TimeDelta::hours(1);
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the worker looks for deliveries that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queues a delivery to every webhook listening for the events.
+/// Only writes to the database, the worker thread does the sending.
+pub fn enqueue(
+    conn: &Connection,
+    clock: &dyn Clock,
+    webhooks: &[WebhookConfig],
+    user: &Person,
+    events: &[Event],
+) -> rusqlite::Result<()> {
+    let now = clock.now();
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO webhook_queue (url, body, next_attempt, user_id) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for event in events {
+        let payload = event.payload(user);
+        for webhook in webhooks.iter().filter(|webhook| webhook.listens_to(event)) {
+            let body = match &webhook.template {
+                Some(template) => fill_template(template, &payload),
+                None => payload.clone(),
+            };
+            stmt.execute((&webhook.url, json::stringify(body), now, user.id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `{field}` in every string of the template with the field of the payload
+fn fill_template(template: &JsonValue, payload: &JsonValue) -> JsonValue {
+    match template {
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            let mut text = template.to_string();
+            for (key, value) in payload.entries() {
+                text = text.replace(&format!("{{{key}}}"), &value.to_string());
+            }
+            text.into()
+        }
+        JsonValue::Array(values) => values
+            .iter()
+            .map(|value| fill_template(value, payload))
+            .collect::<Vec<_>>()
+            .into(),
+        JsonValue::Object(object) => {
+            let mut filled = JsonValue::new_object();
+            for (key, value) in object.iter() {
+                filled[key] = fill_template(value, payload);
+            }
+            filled
+        }
+        _ => template.clone(),
+    }
+}
+
+/// Sends queued deliveries from a background thread with its own connection
+pub fn spawn_worker(db: PathBuf, webhooks: Vec<WebhookConfig>) {
+    thread::spawn(move || {
+        let conn = open_db(&db);
+        loop {
+            // Counted, and the deliveries are tried again on the next round
+            if deliver_due(&conn, &webhooks, Utc::now()).is_err() {
+                metrics::db_error();
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+/// Tries every delivery that is due, returning the number that succeeded.
+/// Failed deliveries are retried later with exponential backoff.
+pub fn deliver_due(
+    conn: &Connection,
+    webhooks: &[WebhookConfig],
+    now: DateTime<Utc>,
+) -> rusqlite::Result<usize> {
+    let due: Vec<(u64, String, String, u32)> = conn
+        .prepare_cached(
+            "SELECT id, url, body, attempts FROM webhook_queue
+            WHERE next_attempt <= ?1 AND attempts < ?2
+            ORDER BY id",
+        )?
+        .query_map((now, MAX_ATTEMPTS), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut delivered = 0;
+    for (id, url, body, attempts) in due {
+        // The secret is looked up on delivery, so it is never stored in the database
+        let secret = webhooks
+            .iter()
+            .find(|webhook| webhook.url == url)
+            .and_then(|webhook| webhook.secret.as_deref());
+        match send(&url, &body, secret) {
+            Ok(()) => {
+                conn.execute("DELETE FROM webhook_queue WHERE id = ?1", [id])?;
+                delivered += 1;
+            }
+            Err(err) => {
+                let backoff = (FIRST_RETRY * 2i32.pow(attempts.min(16))).min(MAX_RETRY);
+                conn.execute(
+                    "UPDATE webhook_queue
+                    SET attempts = attempts + 1, next_attempt = ?2, last_error = ?3
+                    WHERE id = ?1",
+                    (id, now + backoff, err),
+                )?;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+fn send(url: &str, body: &str, secret: Option<&str>) -> Result<(), String> {
+    let mut request = ureq::post(url)
+        .timeout(TIMEOUT)
+        .set("Content-Type", "application/json");
+    if let Some(secret) = secret {
+        request = request.set(SIGNATURE_HEADER, &signature(secret, body));
+    }
+    request
+        .send_string(body)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the secret
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use rusqlite::Connection;
+    use tiny_http::{Response, Server};
+
+    use super::{deliver_due, enqueue, signature, SIGNATURE_HEADER};
+    use crate::clock::{FixedClock, SystemClock};
+    use crate::config::WebhookConfig;
+    use crate::events::Event;
+    use crate::fixtures::Fixture;
+    use crate::models::Person;
+
+    /// Answers `n` requests with `status`, passing on the body and signature of each
+    fn stand_in(status: u16, n: usize) -> (String, mpsc::Receiver<(String, Option<String>)>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for mut request in server.incoming_requests().take(n) {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let signature = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv(SIGNATURE_HEADER))
+                    .map(|header| header.value.to_string());
+                tx.send((body, signature)).unwrap();
+                request.respond(Response::empty(status)).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn user() -> (Connection, Person) {
+        let conn = Fixture::new()
+            .beeps(1234567890, &["2025-02-10 08:00"])
+            .user("ola", &[1234567890])
+            .build();
+        let user = Person::load(&conn, &SystemClock, 1234567890).unwrap();
+        (conn, user)
+    }
+
+    #[test]
+    fn deliveries_are_filtered_templated_and_signed() {
+        let (conn, user) = user();
+        let (url, rx) = stand_in(200, 1);
+        let webhooks = [WebhookConfig {
+            url,
+            events: vec!["arrival".to_string()],
+            secret: Some("hemmelig".to_string()),
+            template: Some(json::object! { text: "{username} kom {date}" }),
+        }];
+
+        enqueue(
+            &conn,
+            &SystemClock,
+            &webhooks,
+            &user,
+            &[Event::Beep, Event::Arrival],
+        )
+        .unwrap();
+        assert_eq!(deliver_due(&conn, &webhooks, Utc::now()).unwrap(), 1);
+
+        let (body, received_signature) = rx.recv().unwrap();
+        let expected = format!("{{\"text\":\"ola kom {}\"}}", user.stats.latest.date);
+        assert_eq!(body, expected);
+        assert_eq!(received_signature, Some(signature("hemmelig", &body)));
+        // Delivered, so nothing is left to send
+        assert_eq!(deliver_due(&conn, &webhooks, Utc::now()).unwrap(), 0);
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_later() {
+        let (conn, user) = user();
+        let (url, rx) = stand_in(500, 2);
+        let webhooks = [WebhookConfig {
+            url,
+            events: vec![],
+            secret: None,
+            template: None,
+        }];
+
+        enqueue(&conn, &SystemClock, &webhooks, &user, &[Event::Beep]).unwrap();
+        let now = Utc::now();
+        assert_eq!(deliver_due(&conn, &webhooks, now).unwrap(), 0);
+        let (body, signature) = rx.recv().unwrap();
+        assert_eq!(json::parse(&body).unwrap()["event"], "beep");
+        assert_eq!(signature, None);
+
+        // Not retried before the backoff has passed
+        assert_eq!(deliver_due(&conn, &webhooks, now).unwrap(), 0);
+        assert!(rx.try_recv().is_err());
+        deliver_due(&conn, &webhooks, now + TimeDelta::minutes(1)).unwrap();
+        assert!(rx.recv().is_ok());
+        let attempts: u32 = conn
+            .query_row("SELECT attempts FROM webhook_queue", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn deliveries_are_queued_at_the_time_of_the_clock() {
+        let (conn, user) = user();
+        let webhooks = [WebhookConfig {
+            url: "http://127.0.0.1:1/hook".to_string(),
+            events: vec![],
+            secret: None,
+            template: None,
+        }];
+        let now = Utc::now() + TimeDelta::days(30);
+
+        enqueue(
+            &conn,
+            &FixedClock::new(now),
+            &webhooks,
+            &user,
+            &[Event::Beep],
+        )
+        .unwrap();
+        let next_attempt: DateTime<Utc> = conn
+            .query_row("SELECT next_attempt FROM webhook_queue", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(next_attempt, now);
+
+        // Database errors are returned to the worker, which tries again later
+        conn.execute("DROP TABLE webhook_queue", ()).unwrap();
+        assert!(deliver_due(&conn, &webhooks, now).is_err());
+    }
+}